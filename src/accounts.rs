@@ -0,0 +1,459 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use color_eyre::{eyre::eyre, Result};
+use console::style;
+use serde_json::Value as JsonValue;
+use sp_core::crypto::{AccountId32, Ss58Codec};
+use sp_core::Pair as _;
+
+use crate::cli::Cli;
+use crate::{
+    free_balance_from_account_storage, json_hex_bytes, read_matching_entries,
+    scale_u128_storage_hex, storage_prefix, system_account_info_with_free_balance,
+    system_account_storage_key, u128_le_from_first_16, ToHex, TopFilter, PLANCK_PER_CTC,
+};
+
+/// An account given as an SS58 address, a `0x`-prefixed 32-byte account id,
+/// or an sr25519 secret URI (`//Alice`, a mnemonic, ...).
+pub fn parse_account(s: &str) -> Result<[u8; 32]> {
+    let s = s.trim();
+    if let Some(digits) = s.strip_prefix("0x").filter(|d| d.len() == 64) {
+        let mut account = [0u8; 32];
+        hex::decode_to_slice(digits, &mut account)
+            .map_err(|e| eyre!("invalid account id `{s}`: {e}"))?;
+        return Ok(account);
+    }
+    if let Ok(account) = AccountId32::from_ss58check(s) {
+        return Ok(account.into());
+    }
+    sp_core::sr25519::Pair::from_string(s, None)
+        .map(|pair| pair.public().0)
+        .map_err(|e| {
+            eyre!("`{s}` is not an SS58 address, 0x-prefixed account id or secret URI ({e:?})")
+        })
+}
+
+/// A balance in planck, or in CTC with a `CTC` suffix (`1.5CTC`, `100 ctc`).
+pub fn parse_amount(s: &str) -> Result<u128> {
+    let s = s.trim();
+    let invalid = || eyre!("invalid amount `{s}`: expected planck or e.g. `1.5CTC`");
+    let lower = s.to_ascii_lowercase();
+    if let Some(ctc) = lower.strip_suffix("ctc") {
+        let (whole, frac) = ctc.trim().split_once('.').unwrap_or((ctc.trim(), ""));
+        if frac.len() > 18 || !frac.chars().all(|c| c.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        let whole: u128 = whole.parse().map_err(|_| invalid())?;
+        let frac: u128 = if frac.is_empty() {
+            0
+        } else {
+            format!("{frac:0<18}").parse().map_err(|_| invalid())?
+        };
+        whole
+            .checked_mul(PLANCK_PER_CTC)
+            .and_then(|w| w.checked_add(frac))
+            .ok_or_else(invalid)
+    } else {
+        lower
+            .strip_suffix("planck")
+            .unwrap_or(&lower)
+            .trim()
+            .replace('_', "")
+            .parse()
+            .map_err(|_| invalid())
+    }
+}
+
+/// An account to give `amount` free balance on the fork.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Funding {
+    pub account: [u8; 32],
+    pub amount: u128,
+}
+
+fn parse_funding(entry: &str) -> Result<Funding> {
+    let (account, amount) = entry
+        .split_once('=')
+        .or_else(|| entry.split_once(','))
+        .ok_or_else(|| eyre!("expected `<account>=<amount>`, got `{entry}`"))?;
+    Ok(Funding {
+        account: parse_account(account)?,
+        amount: parse_amount(amount)?,
+    })
+}
+
+/// `account,amount` lines; blank lines, `#` comments and an
+/// `account,amount` header are skipped.
+fn parse_fund_csv(text: &str) -> Result<Vec<Funding>> {
+    text.lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+        .filter(|(_, line)| {
+            !line.is_empty()
+                && !line.starts_with('#')
+                && !line.replace(' ', "").eq_ignore_ascii_case("account,amount")
+        })
+        .map(|(line_no, line)| parse_funding(line).map_err(|e| eyre!("line {line_no}: {e}")))
+        .collect()
+}
+
+/// The accounts funded with `--fund-file` and `--fund`, in that order.
+pub fn funding_from_cli(cli: &Cli) -> Result<Vec<Funding>> {
+    let mut funding = Vec::new();
+    if let Some(path) = &cli.fund_file {
+        funding.extend(read_fund_file(path)?);
+    }
+    for entry in cli.fund.iter().flatten() {
+        funding.push(parse_funding(entry).map_err(|e| eyre!("invalid --fund: {e}"))?);
+    }
+    Ok(funding)
+}
+
+fn read_fund_file(path: &Path) -> Result<Vec<Funding>> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| eyre!("failed to read {}: {e}", path.display()))?;
+    parse_fund_csv(&text).map_err(|e| eyre!("{}: {e}", path.display()))
+}
+
+/// An encoded `AccountInfo` with its `free` balance replaced, keeping the
+/// nonce, ref-counts and other balances of an existing account. A new account
+/// gets the balances pallet's provider reference.
+fn account_info_with_free_balance(existing: Option<&[u8]>, free: u128) -> String {
+    match existing {
+        Some(bytes) if bytes.len() >= 32 => {
+            let mut info = bytes.to_vec();
+            info[16..32].copy_from_slice(&free.to_le_bytes());
+            if info[8..12] == [0; 4] {
+                info[8..12].copy_from_slice(&1u32.to_le_bytes()); // providers
+            }
+            info.to_hex()
+        }
+        _ => system_account_info_with_free_balance(free),
+    }
+}
+
+/// Set each funded account's free balance (the last entry for an account
+/// wins) and move `Balances.TotalIssuance` by the net change, reading the
+/// previous values from `overrides` (e.g. accounts moved there by
+/// `--impersonate`) or else through `merged_value`.
+pub fn apply_funding(
+    funding: &[Funding],
+    merged_value: impl Fn(&str) -> Option<JsonValue>,
+    issuance_key: &str,
+    overrides: &mut serde_json::Map<String, JsonValue>,
+) {
+    let mut amounts: HashMap<[u8; 32], u128> = HashMap::new();
+    for f in funding {
+        amounts.insert(f.account, f.amount);
+    }
+
+    let current = |key: &str, overrides: &serde_json::Map<String, JsonValue>| {
+        overrides.get(key).cloned().or_else(|| merged_value(key))
+    };
+
+    let old_issuance = current(issuance_key, overrides)
+        .as_ref()
+        .and_then(json_hex_bytes)
+        .and_then(|b| u128_le_from_first_16(&b))
+        .unwrap_or(0);
+
+    let mut issuance = old_issuance;
+    for (account, amount) in &amounts {
+        let key = system_account_storage_key(account);
+        let existing = current(&key, overrides).as_ref().and_then(json_hex_bytes);
+        let old_free = existing
+            .as_deref()
+            .and_then(free_balance_from_account_storage)
+            .unwrap_or(0);
+        issuance = issuance.saturating_sub(old_free).saturating_add(*amount);
+        overrides.insert(
+            key,
+            account_info_with_free_balance(existing.as_deref(), *amount).into(),
+        );
+    }
+    overrides.insert(
+        issuance_key.to_owned(),
+        scale_u128_storage_hex(issuance).into(),
+    );
+
+    println!(
+        "{}",
+        style(format!(
+            "Funded {} account(s); TotalIssuance {old_issuance} -> {issuance}",
+            amounts.len()
+        ))
+        .green()
+    );
+}
+
+/// `free + reserved` of an encoded `AccountInfo`: the account's share of
+/// `Balances.TotalIssuance` (held balance is part of `reserved`).
+pub fn account_total(bytes: &[u8]) -> Option<u128> {
+    let free = u128_le_from_first_16(bytes.get(16..)?)?;
+    let reserved = u128_le_from_first_16(bytes.get(32..)?)?;
+    Some(free.saturating_add(reserved))
+}
+
+/// Running sum of the `System.Account` balances [`reconcile_issuance`] sees.
+#[derive(Default)]
+struct Tally {
+    total: u128,
+    accounts: usize,
+    /// Accounts whose value doesn't decode, and the first one's key.
+    undecodable: usize,
+    first_undecodable: Option<String>,
+}
+
+impl Tally {
+    fn add(&mut self, key: &str, value: Option<&str>) {
+        let total = value
+            .and_then(|v| hex::decode(v.trim_start_matches("0x")).ok())
+            .and_then(|bytes| account_total(&bytes));
+        match total {
+            Some(total) => self.total = self.total.saturating_add(total),
+            None => {
+                self.undecodable += 1;
+                self.first_undecodable.get_or_insert_with(|| key.to_owned());
+            }
+        }
+        self.accounts += 1;
+    }
+}
+
+/// Set `Balances.TotalIssuance` to the sum of every account's free and
+/// reserved balance in the fork's final top state (storage, base spec and
+/// overrides merged as when writing it), reporting the correction. Fails if
+/// an account doesn't decode, rather than setting an issuance that misses it.
+pub fn reconcile_issuance(
+    storage_path: Option<&Path>,
+    base_top: &serde_json::Map<String, JsonValue>,
+    filter: &TopFilter,
+    issuance_key: &str,
+    overrides: &mut serde_json::Map<String, JsonValue>,
+) -> Result<()> {
+    let account_prefix = storage_prefix("System", "Account");
+    let mut tally = Tally::default();
+    let mut old_issuance = overrides.get(issuance_key).cloned();
+    // Storage entries the base spec also has, so the base's copy isn't counted.
+    let mut shadowed = HashSet::new();
+    if let Some(path) = storage_path {
+        read_matching_entries(path, |key, value| {
+            if !filter.keeps_storage_key(key) {
+                return false;
+            }
+            if base_top.contains_key(key) {
+                shadowed.insert(key.to_owned());
+            }
+            if key == issuance_key && old_issuance.is_none() {
+                old_issuance = Some(value.into());
+            }
+            if key.starts_with(&account_prefix) && !overrides.contains_key(key) {
+                tally.add(key, Some(value));
+            }
+            false
+        })?;
+    }
+    for (key, value) in base_top {
+        if shadowed.contains(key) || overrides.contains_key(key) || !filter.keeps_base_key(key) {
+            continue;
+        }
+        if key == issuance_key && old_issuance.is_none() {
+            old_issuance = Some(value.clone());
+        }
+        if key.starts_with(&account_prefix) {
+            tally.add(key, value.as_str());
+        }
+    }
+    for (key, value) in overrides.iter() {
+        if key.starts_with(&account_prefix) {
+            tally.add(key, value.as_str());
+        }
+    }
+
+    if let Some(first) = &tally.first_undecodable {
+        return Err(eyre!(
+            "{} System.Account value(s) don't decode as an AccountInfo (first: {first}); \
+             can't reconcile TotalIssuance",
+            tally.undecodable
+        ));
+    }
+    let Tally {
+        total, accounts, ..
+    } = tally;
+    let old_issuance = old_issuance
+        .as_ref()
+        .and_then(json_hex_bytes)
+        .and_then(|b| u128_le_from_first_16(&b))
+        .unwrap_or(0);
+    overrides.insert(
+        issuance_key.to_owned(),
+        scale_u128_storage_hex(total).into(),
+    );
+
+    let message = if total == old_issuance {
+        format!("TotalIssuance matches the balances of {accounts} account(s): {total}")
+    } else {
+        let (sign, delta) = if total > old_issuance {
+            ('+', total - old_issuance)
+        } else {
+            ('-', old_issuance - total)
+        };
+        format!(
+            "Reconciled TotalIssuance over {accounts} account(s): {old_issuance} -> {total} ({sign}{delta})"
+        )
+    };
+    println!("{}", style(message).green());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{account_id_from_seed_hex, ALICE_SEED_HEX, TEN_CTC_PLANCK};
+
+    const ALICE_SS58: &str = "5GrwvaEF5zXb26Fz9rcQpDWS57CtERHpNehXCPcNoHGKutQY";
+
+    #[test]
+    fn accounts_parse_from_ss58_hex_and_secret_uri() {
+        let alice = account_id_from_seed_hex(ALICE_SEED_HEX).unwrap();
+        assert_eq!(parse_account(ALICE_SS58).unwrap(), alice);
+        assert_eq!(parse_account("//Alice").unwrap(), alice);
+        assert_eq!(parse_account(&alice.to_hex()).unwrap(), alice);
+        assert!(parse_account("not an account").is_err());
+    }
+
+    #[test]
+    fn amounts_parse_as_planck_or_ctc() {
+        assert_eq!(parse_amount("10CTC").unwrap(), TEN_CTC_PLANCK);
+        assert_eq!(parse_amount("1.5 ctc").unwrap(), 1_500_000_000_000_000_000);
+        assert_eq!(parse_amount("1_000").unwrap(), 1000);
+        assert_eq!(parse_amount("7planck").unwrap(), 7);
+        assert!(parse_amount("0.0000000000000000001CTC").is_err());
+        assert!(parse_amount("-1").is_err());
+    }
+
+    #[test]
+    fn fund_csv_skips_comments_and_header() {
+        let funding = parse_fund_csv(&format!(
+            "account,amount\n# QA accounts\n{ALICE_SS58},1CTC\n\n//Bob, 5\n"
+        ))
+        .unwrap();
+        assert_eq!(funding.len(), 2);
+        assert_eq!(funding[0].amount, PLANCK_PER_CTC);
+        assert_eq!(funding[1].amount, 5);
+        let err = parse_fund_csv("//Alice,lots").unwrap_err();
+        assert!(err.to_string().starts_with("line 1:"), "{err}");
+    }
+
+    #[test]
+    fn funding_keeps_issuance_consistent() {
+        let alice = parse_account("//Alice").unwrap();
+        let bob = parse_account("//Bob").unwrap();
+        let issuance_key = "0x1ssuance";
+        let alice_key = system_account_storage_key(&alice);
+        let state: HashMap<String, JsonValue> = HashMap::from([
+            (issuance_key.to_owned(), scale_u128_storage_hex(1000).into()),
+            (
+                alice_key.clone(),
+                system_account_info_with_free_balance(300).into(),
+            ),
+        ]);
+
+        let mut overrides = serde_json::Map::new();
+        apply_funding(
+            &[
+                Funding {
+                    account: alice,
+                    amount: 50,
+                },
+                Funding {
+                    account: bob,
+                    amount: 20,
+                },
+                Funding {
+                    account: alice,
+                    amount: 100,
+                },
+            ],
+            |key| state.get(key).cloned(),
+            issuance_key,
+            &mut overrides,
+        );
+
+        // 1000 - 300 (Alice's old free) + 100 + 20
+        assert_eq!(overrides[issuance_key], scale_u128_storage_hex(820));
+        assert_eq!(
+            overrides[&alice_key],
+            system_account_info_with_free_balance(100)
+        );
+    }
+
+    #[test]
+    fn funding_an_impersonation_target_keeps_its_moved_account() {
+        let alice = parse_account("//Alice").unwrap();
+        let bob = parse_account("//Bob").unwrap();
+        let issuance_key = "0x1ssuance";
+        // Alice's account, with a nonce and reserved balance, as `--impersonate
+        // <alice>=//Bob` leaves it: moved onto Bob's key in the overrides.
+        let mut info = hex::decode(&system_account_info_with_free_balance(300)[2..]).unwrap();
+        info[..4].copy_from_slice(&5u32.to_le_bytes());
+        info[32..48].copy_from_slice(&25u128.to_le_bytes());
+        let state: HashMap<String, JsonValue> = HashMap::from([
+            (issuance_key.to_owned(), scale_u128_storage_hex(1000).into()),
+            (system_account_storage_key(&alice), info.to_hex().into()),
+        ]);
+        let bob_key = system_account_storage_key(&bob);
+        let mut overrides = serde_json::Map::new();
+        overrides.insert(bob_key.clone(), info.to_hex().into());
+
+        apply_funding(
+            &[Funding {
+                account: bob,
+                amount: 100,
+            }],
+            |key| state.get(key).cloned(),
+            issuance_key,
+            &mut overrides,
+        );
+
+        info[16..32].copy_from_slice(&100u128.to_le_bytes());
+        assert_eq!(overrides[&bob_key], info.to_hex());
+        // 1000 - 300 (the moved account's old free) + 100
+        assert_eq!(overrides[issuance_key], scale_u128_storage_hex(800));
+    }
+
+    #[test]
+    fn account_totals_include_reserved_balance() {
+        let mut info = hex::decode(&system_account_info_with_free_balance(300)[2..]).unwrap();
+        info[32..48].copy_from_slice(&25u128.to_le_bytes());
+        info[48..64].copy_from_slice(&1000u128.to_le_bytes()); // frozen doesn't count
+        assert_eq!(account_total(&info), Some(325));
+        assert_eq!(account_total(&info[..40]), None);
+    }
+
+    #[test]
+    fn undecodable_accounts_fail_the_reconciliation() {
+        let filter = TopFilter {
+            include_prefixes: Vec::new(),
+            replaced_prefixes: Vec::new(),
+            exclude_prefixes: Vec::new(),
+            remove_exact: HashSet::new(),
+        };
+        let issuance_key = storage_prefix("Balances", "TotalIssuance");
+        let account_key = |n: u8| format!("{}{n:02x}", storage_prefix("System", "Account"));
+        let mut base_top = serde_json::Map::new();
+        base_top.insert(
+            account_key(1),
+            system_account_info_with_free_balance(300).into(),
+        );
+        let mut overrides = serde_json::Map::new();
+        reconcile_issuance(None, &base_top, &filter, &issuance_key, &mut overrides).unwrap();
+        assert_eq!(overrides[&issuance_key], scale_u128_storage_hex(300));
+
+        base_top.insert(account_key(2), "0x0102".into());
+        let err = reconcile_issuance(None, &base_top, &filter, &issuance_key, &mut overrides)
+            .unwrap_err();
+        assert!(err.to_string().contains(&account_key(2)));
+    }
+}
@@ -0,0 +1,211 @@
+use color_eyre::{eyre::eyre, Result};
+use console::style;
+use serde_json::Value as JsonValue;
+use sp_core::hashing::blake2_64;
+
+use crate::cli::Cli;
+use crate::ChainSpec;
+
+/// Chain-spec fields an extension may not shadow.
+const SPEC_FIELDS: [&str; 9] = [
+    "name",
+    "id",
+    "chainType",
+    "bootNodes",
+    "telemetryEndpoints",
+    "protocolId",
+    "properties",
+    "codeSubstitutes",
+    "genesis",
+];
+
+/// A protocol id apart from the original network's: `base` with a suffix
+/// hashed from it and the fork's `name` and `id`, so the fork's nodes never
+/// gossip with the original chain's peers while rebuilding the same fork
+/// gives the same id.
+pub fn unique_protocol_id(base: &str, name: &str, id: &str) -> String {
+    let seed = [base, name, id].join("\0");
+    format!(
+        "{base}-fork-{}",
+        hex::encode(&blake2_64(seed.as_bytes())[..4])
+    )
+}
+
+/// Fail if `spec` has the original chain's name, id and protocol id (unless
+/// `allow`), as its nodes would then pass for the original chain's; warn if
+/// only the protocol id is shared.
+pub fn check_isolated(spec: &ChainSpec, original: &ChainSpec, allow: bool) -> Result<()> {
+    if spec.protocol_id != original.protocol_id {
+        return Ok(());
+    }
+    if spec.name == original.name && spec.id == original.id && !allow {
+        return Err(eyre!(
+            "the fork has the original chain's name, id and protocol id, so its nodes could \
+             join the original network; change one of --name, --id or --protocol-id, or pass \
+             --allow-original-network"
+        ));
+    }
+    println!(
+        "{}",
+        style("Warning: the fork shares the original chain's protocol id").yellow()
+    );
+    Ok(())
+}
+
+/// `<url> [verbosity]`, as the node's `--telemetry-url` takes it.
+fn parse_telemetry_endpoint(s: &str) -> Result<(String, u8)> {
+    let mut parts = s.split_whitespace();
+    let url = parts
+        .next()
+        .ok_or_else(|| eyre!("empty telemetry endpoint"))?;
+    let verbosity = match parts.next() {
+        Some(v) => v
+            .parse()
+            .map_err(|_| eyre!("invalid verbosity `{v}` in `{s}`"))?,
+        None => 0,
+    };
+    if parts.next().is_some() {
+        return Err(eyre!("expected `<url> [verbosity]`, got `{s}`"));
+    }
+    Ok((url.to_owned(), verbosity))
+}
+
+/// `<key>=<JSON value>`; a value that isn't JSON is taken as a string.
+fn parse_extension(s: &str) -> Result<(String, JsonValue)> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or_else(|| eyre!("expected `<key>=<JSON value>`, got `{s}`"))?;
+    if SPEC_FIELDS.contains(&key) {
+        return Err(eyre!("`{key}` is not an extension; use its own flag"));
+    }
+    let value = serde_json::from_str(value).unwrap_or_else(|_| JsonValue::from(value));
+    Ok((key.to_owned(), value))
+}
+
+/// The fork's chain-spec fields besides its genesis, as set on the command
+/// line; anything unset keeps the base spec's value.
+pub struct SpecMetadata {
+    properties: Vec<(&'static str, JsonValue)>,
+    chain_type: Option<String>,
+    telemetry_endpoints: Option<Vec<(String, u8)>>,
+    boot_nodes: Option<Vec<String>>,
+    extensions: Vec<(String, JsonValue)>,
+}
+
+impl SpecMetadata {
+    pub fn from_cli(cli: &Cli) -> Result<Self> {
+        let properties = [
+            ("tokenSymbol", cli.token_symbol.clone().map(JsonValue::from)),
+            ("tokenDecimals", cli.token_decimals.map(JsonValue::from)),
+            ("ss58Format", cli.ss58_format.map(JsonValue::from)),
+        ]
+        .into_iter()
+        .filter_map(|(key, value)| Some((key, value?)))
+        .collect();
+        let telemetry_endpoints = cli
+            .telemetry_endpoint
+            .as_ref()
+            .map(|endpoints| {
+                endpoints
+                    .iter()
+                    .map(|s| {
+                        parse_telemetry_endpoint(s)
+                            .map_err(|e| eyre!("invalid --telemetry-endpoint: {e}"))
+                    })
+                    .collect::<Result<Vec<_>>>()
+            })
+            .transpose()?;
+        let extensions = cli
+            .extension
+            .iter()
+            .flatten()
+            .map(|s| parse_extension(s).map_err(|e| eyre!("invalid --extension: {e}")))
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            properties,
+            chain_type: cli.chain_type.clone(),
+            telemetry_endpoints,
+            boot_nodes: cli.boot_node.clone(),
+            extensions,
+        })
+    }
+
+    pub fn apply(self, spec: &mut ChainSpec) {
+        if !self.properties.is_empty() {
+            let mut properties = match spec.properties.take() {
+                Some(JsonValue::Object(map)) => map,
+                _ => serde_json::Map::new(),
+            };
+            for (key, value) in self.properties {
+                properties.insert(key.to_owned(), value);
+            }
+            spec.properties = Some(JsonValue::Object(properties));
+        }
+        if let Some(chain_type) = self.chain_type {
+            spec.chain_type = chain_type;
+        }
+        if let Some(endpoints) = self.telemetry_endpoints {
+            spec.telemetry_endpoints = Some(endpoints);
+        }
+        if let Some(boot_nodes) = self.boot_nodes {
+            spec.boot_nodes = boot_nodes;
+        }
+        if !self.extensions.is_empty() {
+            let mut extensions = match spec.extensions.take() {
+                Some(JsonValue::Object(map)) => map,
+                _ => serde_json::Map::new(),
+            };
+            for (key, value) in self.extensions {
+                extensions.insert(key, value);
+            }
+            spec.extensions = Some(JsonValue::Object(extensions));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn protocol_ids_get_a_suffix_from_the_fork() {
+        let id = unique_protocol_id("cc3", "Creditcoin-fork", "cc3-fork");
+        assert!(id.starts_with("cc3-fork-"), "{id}");
+        assert_eq!(id.len(), "cc3-fork-".len() + 8);
+        assert_eq!(id, unique_protocol_id("cc3", "Creditcoin-fork", "cc3-fork"));
+        assert_ne!(
+            id,
+            unique_protocol_id("cc3", "Creditcoin-fork", "cc3-fork-2")
+        );
+        assert_ne!(id, unique_protocol_id("cc3", "Other-fork", "cc3-fork"));
+    }
+
+    #[test]
+    fn telemetry_endpoints_and_extensions_parse() {
+        assert_eq!(
+            parse_telemetry_endpoint("wss://telemetry.example.com/submit/ 1").unwrap(),
+            ("wss://telemetry.example.com/submit/".to_owned(), 1)
+        );
+        assert_eq!(
+            parse_telemetry_endpoint("wss://telemetry.example.com/submit/").unwrap(),
+            ("wss://telemetry.example.com/submit/".to_owned(), 0)
+        );
+        assert!(parse_telemetry_endpoint("wss://t.example.com loud").is_err());
+
+        assert_eq!(
+            parse_extension("badBlocks=[\"0x01\"]").unwrap(),
+            ("badBlocks".to_owned(), json!(["0x01"]))
+        );
+        assert_eq!(
+            parse_extension("forkBlocks=null").unwrap(),
+            ("forkBlocks".to_owned(), JsonValue::Null)
+        );
+        assert_eq!(
+            parse_extension("relay_chain=rococo").unwrap(),
+            ("relay_chain".to_owned(), json!("rococo"))
+        );
+        assert!(parse_extension("genesis={}").is_err());
+    }
+}
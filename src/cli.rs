@@ -1,24 +1,78 @@
 use std::{
     borrow::Cow,
     convert::Infallible,
+    ffi::OsStr,
     fmt,
-    path::PathBuf,
+    path::{Path, PathBuf},
     str::FromStr,
     time::{Duration, Instant},
 };
 
-use color_eyre::{Report, Result};
+use color_eyre::{eyre::eyre, Report, Result};
 use indicatif::{ProgressBar, ProgressStyle};
 use sp_core::H256;
 
+use crate::recipe::Recipe;
 use crate::Chain;
 
+/// The chain types a chain-spec may declare.
+pub const CHAIN_TYPES: [&str; 3] = ["Development", "Local", "Live"];
+
 #[derive(Clone, Debug)]
 pub enum StorageFile {
     None,
     Path(PathBuf),
 }
 
+/// A block given by hash (`0x...`), number, `finalized`, `best` or
+/// `finalized-N` (N blocks before the finalized head).
+#[derive(Clone, Debug, PartialEq)]
+pub enum BlockRef {
+    Hash(H256),
+    Number(u32),
+    Finalized,
+    Best,
+    BeforeFinalized(u32),
+}
+
+impl FromStr for BlockRef {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let expected = || {
+            eyre!(
+                "expected a block hash or number, `finalized`, `best` or `finalized-N`, got `{s}`"
+            )
+        };
+        match s {
+            "finalized" => Ok(BlockRef::Finalized),
+            "best" => Ok(BlockRef::Best),
+            _ if s.starts_with("0x") => H256::from_str(s)
+                .map(BlockRef::Hash)
+                .map_err(|e| eyre!("invalid block hash `{s}`: {e:?}")),
+            _ => match s.strip_prefix("finalized-") {
+                Some(n) => n
+                    .parse()
+                    .map(BlockRef::BeforeFinalized)
+                    .map_err(|_| expected()),
+                None => s.parse().map(BlockRef::Number).map_err(|_| expected()),
+            },
+        }
+    }
+}
+
+impl fmt::Display for BlockRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockRef::Hash(hash) => write!(f, "{hash:?}"),
+            BlockRef::Number(number) => write!(f, "#{number}"),
+            BlockRef::Finalized => write!(f, "finalized"),
+            BlockRef::Best => write!(f, "best"),
+            BlockRef::BeforeFinalized(n) => write!(f, "finalized-{n}"),
+        }
+    }
+}
+
 impl FromStr for StorageFile {
     type Err = Infallible;
 
@@ -32,22 +86,59 @@ impl FromStr for StorageFile {
 }
 
 #[derive(clap::Parser)]
+#[clap(subcommand_negates_reqs = true)]
 pub struct Cli {
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+
+    /// Path to a TOML fork recipe. Its keys are the long flag names
+    /// (e.g. `bin`, `orig`, `exclude-pallets`); flags passed on the
+    /// command line override the recipe's values (and its conflicting ones),
+    /// and `--no-<switch>` turns off a switch the recipe turns on.
+    #[clap(long)]
+    pub config: Option<PathBuf>,
     /// Path to the creditcoin-node binary to use
     /// for chain-spec creation.
-    #[clap(long = "bin")]
-    pub binary: PathBuf,
+    #[clap(long = "bin", required_unless_present = "config")]
+    pub binary: Option<PathBuf>,
     /// Path to the runtime WASM blob to use
     /// in the forked chain. If omitted this will
     #[clap(long)]
     pub runtime: Option<PathBuf>,
+    /// Block whose runtime code the fork uses, instead of the code of the
+    /// state it is built from: a hash, a number, `finalized`, `best` or
+    /// `finalized-N` (N blocks before the finalized head), as for `--at`.
+    /// Fetched over `--rpc`.
+    #[clap(long, conflicts_with = "runtime")]
+    pub runtime_at: Option<BlockRef>,
+    /// Runtime the fork's nodes run instead of the on-chain code from a
+    /// block on, as `<block number>=<wasm path>` (repeatable), added to the
+    /// chain-spec's `codeSubstitutes`. Validated like `--runtime`.
+    #[clap(long, value_name = "BLOCK=PATH")]
+    pub code_substitute: Option<Vec<String>>,
+    /// Drop the base chain-spec's `codeSubstitutes`
+    #[clap(long)]
+    pub clear_code_substitutes: bool,
+    /// Compress plain wasm `--runtime` and `--code-substitute` blobs into the
+    /// zstd-compressed blob format nodes accept.
+    #[clap(long)]
+    pub compress_runtime: bool,
+    /// Largest runtime blob, in bytes, accepted for `--runtime` and
+    /// `--code-substitute`
+    #[clap(long, default_value_t = 5 * 1024 * 1024)]
+    pub max_code_size: usize,
+    /// Fork even if the runtime is for a different chain than the original
+    /// (its `spec_name` differs) or than `--bin`'s own runtime, warning
+    /// instead of failing.
+    #[clap(long)]
+    pub allow_runtime_mismatch: bool,
     /// Path to write the fork's chain-spec to
     #[clap(short, long, default_value = "fork.json")]
     pub out: PathBuf,
     /// Name of the original chain to fork from
     /// (e.g. "dev", "test", "main")
-    #[clap(long = "orig")]
-    pub original_chain: Chain,
+    #[clap(long = "orig", required_unless_present = "config")]
+    pub original_chain: Option<Chain>,
     /// Name of the chain to use as the base for the fork's
     /// chain-spec
     #[clap(long = "base", default_value_t = Chain::Dev)]
@@ -60,15 +151,53 @@ pub struct Cli {
     /// Pass `none` to skip fetching state entirely.
     #[clap(long)]
     pub storage: Option<StorageFile>,
-    /// Block hash to fetch the on-chain state from.
+    /// Block to fetch the on-chain state from: a hash, a number,
+    /// `finalized`, `best` or `finalized-N` (N blocks before the finalized
+    /// head); default `finalized`. Every request of the fetch is pinned to
+    /// it, and the block is recorded next to the storage file: reusing the
+    /// file with a different `--at` fails.
     #[clap(long)]
-    pub at: Option<H256>,
+    pub at: Option<BlockRef>,
     /// Name for the new, forked chain. Defaults to `{original}-fork`.
     #[clap(long)]
     pub name: Option<String>,
     /// Chain ID for the new, forked chain. Defaults to `{original}-fork`.
     #[clap(long)]
     pub id: Option<String>,
+    /// Protocol id for the fork's network. Defaults to the original chain's
+    /// with a suffix derived from the fork's name and id, so the fork's nodes
+    /// never gossip with the original network's and rebuilds keep the id.
+    #[clap(long)]
+    pub protocol_id: Option<String>,
+    /// Emit the fork's chain-spec even if its name, id and protocol id are
+    /// all the original chain's
+    #[clap(long)]
+    pub allow_original_network: bool,
+    /// The fork's `tokenSymbol` property
+    #[clap(long)]
+    pub token_symbol: Option<String>,
+    /// The fork's `tokenDecimals` property
+    #[clap(long)]
+    pub token_decimals: Option<u8>,
+    /// The fork's `ss58Format` property
+    #[clap(long)]
+    pub ss58_format: Option<u16>,
+    /// The fork's chain type. Defaults to the base chain-spec's.
+    #[clap(long, value_parser = CHAIN_TYPES)]
+    pub chain_type: Option<String>,
+    /// Telemetry endpoint of the fork, as `<url> [verbosity]` (repeatable;
+    /// verbosity defaults to 0). The fork has none by default.
+    #[clap(long, value_name = "URL [VERBOSITY]")]
+    pub telemetry_endpoint: Option<Vec<String>>,
+    /// Boot node of the fork, as a multiaddr (repeatable). The fork has none
+    /// by default.
+    #[clap(long, value_name = "MULTIADDR")]
+    pub boot_node: Option<Vec<String>>,
+    /// Chain-spec extension to set, as `<key>=<JSON value>` (repeatable), e.g.
+    /// `badBlocks=["0x..."]` or `forkBlocks=null`. Values that aren't JSON
+    /// are taken as strings.
+    #[clap(long, value_name = "KEY=JSON")]
+    pub extension: Option<Vec<String>>,
 
     /// Url for the live node from which to pull state and other required data.
     /// Port is optional for wss:// (default 443) and ws:// (default 80).
@@ -117,6 +246,139 @@ pub struct Cli {
     #[clap(long)]
     pub no_default_excludes: bool,
 
+    /// Storage items to keep state from, as `Pallet.Item` (or `Pallet.Prefix*`
+    /// for every item of the pallet starting with `Prefix`). Without
+    /// `--pallets`, only these items (and `System.Account`) are kept; with
+    /// it, they are kept in addition to the listed pallets. A listed item wins
+    /// over its pallet's exclusion, except for the validator pallets the fork
+    /// replaces (an error unless `--keep-validators`).
+    #[clap(long)]
+    pub include_items: Option<Vec<String>>,
+
+    /// Storage items to drop state from, as `Pallet.Item` or `Pallet.Prefix*`,
+    /// e.g. to keep all of a pallet except one huge map.
+    #[clap(long)]
+    pub exclude_items: Option<Vec<String>>,
+
+    /// Raw storage entries to set, as `<key>=<value>`. Keys are hex or the
+    /// `Pallet.Item` name of a plain (non-map) item; values are SCALE-encoded
+    /// hex. These win over every other source, including the fork's own
+    /// overrides (`:code`, `Sudo.Key`, ...).
+    #[clap(long, value_name = "KEY=VALUE")]
+    pub set: Option<Vec<String>>,
+
+    /// Typed storage entries to set, as `Pallet.Item[key args]=<JSON value>`
+    /// (no brackets for plain items). The key arguments are a JSON array
+    /// hashed with the item's declared hashers, and the value is SCALE-encoded
+    /// against the runtime metadata's type registry, e.g.
+    /// `Staking.Payee["5Grw..."]={"Account": "5Grw..."}`.
+    #[clap(long, value_name = "ITEM=JSON")]
+    pub set_typed: Option<Vec<String>>,
+
+    /// Storage keys to remove, as hex or `Pallet.Item` (plain items only).
+    #[clap(long)]
+    pub remove: Option<Vec<String>>,
+
+    /// Storage key prefixes to remove, as hex, `Pallet.Item` or `Pallet`.
+    #[clap(long)]
+    pub remove_prefix: Option<Vec<String>>,
+
+    /// JSON file of overrides: `{"set": {"<key>": "<value>"},
+    /// "set-typed": {"<Pallet.Item[args]>": <JSON value>}, "remove": [...],
+    /// "remove-prefix": [...]}`, with keys in the same forms as the flags.
+    /// Flags are applied after it.
+    #[clap(long)]
+    pub overrides_file: Option<PathBuf>,
+
+    /// Accounts to fund, as `<account>=<amount>`. Accounts are SS58
+    /// addresses, `0x`-prefixed account ids or secret URIs (e.g. `//Alice`);
+    /// amounts are planck, or CTC with a `CTC` suffix (e.g. `1.5CTC`). The
+    /// free balance is set to the amount and `Balances.TotalIssuance` is
+    /// adjusted by the net change.
+    #[clap(long, value_name = "ACCOUNT=AMOUNT")]
+    pub fund: Option<Vec<String>>,
+
+    /// CSV file of accounts to fund, one `account,amount` per line (same
+    /// forms as `--fund`). `--fund` entries are applied after it.
+    #[clap(long)]
+    pub fund_file: Option<PathBuf>,
+
+    /// Report balance locks, freezes and holds whose owning pallet's state
+    /// the fork drops (e.g. `staking` locks of accounts without a ledger).
+    #[clap(long)]
+    pub report_orphaned_locks: bool,
+
+    /// Remove the locks, freezes and holds `--report-orphaned-locks` finds,
+    /// recomputing the accounts' `frozen` balance and returning released
+    /// holds to `free`.
+    #[clap(long)]
+    pub remove_orphaned_locks: bool,
+
+    /// After all other changes, set `Balances.TotalIssuance` to the sum of
+    /// every `System.Account`'s free and reserved balance in the fork's final
+    /// state, reporting the discrepancy corrected. This replaces any
+    /// `--set` of `TotalIssuance`.
+    #[clap(long)]
+    pub reconcile_issuance: bool,
+
+    /// Run `verify`'s checks on the fork's chain-spec once it is written,
+    /// failing the build if any of them fails.
+    #[clap(long)]
+    pub verify: bool,
+
+    /// Act as an account whose key we don't hold, as
+    /// `<account>=<secret URI>`: the account's state (every entry keyed by
+    /// it in maps whose metadata declares an `AccountId32` key, such as
+    /// `System.Account`, `Balances.Locks` or `Proxy.Proxies`) is moved onto
+    /// the URI's sr25519 account, and the account id is rewritten inside
+    /// values whose types contain account ids.
+    #[clap(long, value_name = "ACCOUNT=URI")]
+    pub impersonate: Option<Vec<String>>,
+
+    /// The fork's sudo key, as an SS58 address, `0x`-prefixed account id or
+    /// secret URI. Defaults to Alice. On runtimes without the Sudo pallet the
+    /// account instead becomes the sole (and prime) member of every
+    /// collective and membership pallet.
+    #[clap(long, value_name = "ACCOUNT")]
+    pub sudo: Option<String>,
+
+    /// Leave the original chain's sudo key (and collectives) untouched.
+    #[clap(long, conflicts_with = "sudo")]
+    pub no_sudo: bool,
+
+    /// Keep the original chain's consensus and staking state (Babe, Grandpa,
+    /// Session, Staking, ImOnline, VoterList) instead of injecting a new
+    /// validator set. The fork only makes progress once nodes holding the
+    /// original validators' session keys are running.
+    #[clap(long, conflicts_with_all = ["validators", "validators_file"])]
+    pub keep_validators: bool,
+
+    /// Keep every staker's `Staking.Bonded`, `Ledger` and `Payee` (not only
+    /// the nomination pools'), re-point their nominations at the fork's
+    /// validators and rebuild `VoterList` to match, so real accounts can
+    /// unbond and withdraw on the fork. Needs the original chain's state
+    /// (not `--storage none`).
+    #[clap(long, conflicts_with = "keep_validators")]
+    pub preserve_stakers: bool,
+
+    /// Secret URIs (e.g. `//Alice,//Bob,//Charlie`) of the fork's validators.
+    /// Each validator's stash, controller and session keys are derived from
+    /// its URI, and Babe, Grandpa, Session, Staking and VoterList genesis is
+    /// written for the whole set. Without it, the dev chain's validator
+    /// genesis (Alice only) is injected.
+    #[clap(long, value_delimiter = ',')]
+    pub validators: Option<Vec<String>>,
+
+    /// File of validator secret URIs, one per line (`#` starts a comment).
+    /// `--validators` entries are added after it.
+    #[clap(long)]
+    pub validators_file: Option<PathBuf>,
+
+    /// Stake bonded by each of `--validators`, in planck or CTC (e.g.
+    /// `100000CTC`). Validators are funded with the stake plus 10 CTC.
+    #[clap(long, default_value = "100000CTC")]
+    pub validator_stake: String,
+
     /// If set, inject USC-related genesis: Alice/Bob balances and BLS keys,
     /// Attestation (`Attestors` for `--usc-chain-key` only, `ActiveAttestors`, `TargetSampleSize`),
     /// clear Randomness pallet keys, using the configured Alice/Bob hex seeds.
@@ -126,6 +388,201 @@ pub struct Cli {
     /// Only used when `--usc` is set. Default: 3.
     #[clap(long, default_value_t = 3)]
     pub usc_chain_key: u64,
+
+    #[clap(flatten)]
+    pub switches_off: SwitchesOff,
+}
+
+/// `--no-<switch>` flags, turning off a switch the `--config` recipe turns
+/// on (`--default-excludes` for `no-default-excludes`).
+#[derive(clap::Args)]
+pub struct SwitchesOff {
+    #[clap(long, hide = true, conflicts_with = "clear_code_substitutes")]
+    pub no_clear_code_substitutes: bool,
+    #[clap(long, hide = true, conflicts_with = "compress_runtime")]
+    pub no_compress_runtime: bool,
+    #[clap(long, hide = true, conflicts_with = "allow_runtime_mismatch")]
+    pub no_allow_runtime_mismatch: bool,
+    #[clap(long, hide = true, conflicts_with = "allow_original_network")]
+    pub no_allow_original_network: bool,
+    #[clap(long, hide = true, conflicts_with = "no_default_excludes")]
+    pub default_excludes: bool,
+    #[clap(long, hide = true, conflicts_with = "report_orphaned_locks")]
+    pub no_report_orphaned_locks: bool,
+    #[clap(long, hide = true, conflicts_with = "remove_orphaned_locks")]
+    pub no_remove_orphaned_locks: bool,
+    #[clap(long, hide = true, conflicts_with = "reconcile_issuance")]
+    pub no_reconcile_issuance: bool,
+    #[clap(long, hide = true, conflicts_with = "verify")]
+    pub no_verify: bool,
+    #[clap(long, hide = true, conflicts_with = "keep_validators")]
+    pub no_keep_validators: bool,
+    #[clap(long, hide = true, conflicts_with = "preserve_stakers")]
+    pub no_preserve_stakers: bool,
+    #[clap(long, hide = true, conflicts_with = "usc")]
+    pub no_usc: bool,
+}
+
+#[derive(clap::Subcommand)]
+pub enum Command {
+    /// Check a fork's chain-spec: `:code` is a wasm runtime, Babe and Grandpa
+    /// have authorities, `Sudo.Key` is set, `TotalIssuance` matches the
+    /// accounts' balances, every nomination pool has a staking ledger, no
+    /// keys remain under excluded prefixes and the genesis differs from the
+    /// original chain's. Exits non-zero if any check fails.
+    Verify(VerifyArgs),
+    /// Boot a fork's chain-spec with a local node in a temporary base path,
+    /// wait for blocks to be produced and finalized and scan the node's log
+    /// for panics and migration errors.
+    Run(RunArgs),
+    /// Rehearse a runtime upgrade: boot a fork built with the on-chain
+    /// runtime, submit `sudo(system.set_code)` with the new runtime and check
+    /// that the spec version and `System.LastRuntimeUpgrade` moved, showing
+    /// the upgrade's migration events.
+    UpgradeTest(UpgradeTestArgs),
+    /// Run a runtime's migrations (`TryRuntime_on_runtime_upgrade`) against
+    /// a fork's cached state in memory, without a node, reporting their
+    /// weight, storage changes and check failures. Needs the
+    /// `creditcoin-fork-try-upgrade` helper (`try-upgrade/`).
+    TryUpgrade(TryUpgradeArgs),
+}
+
+/// How to run a local node on a fork.
+#[derive(clap::Args)]
+pub struct NodeArgs {
+    /// Path to the node binary to run the fork with
+    #[clap(long = "bin")]
+    pub binary: PathBuf,
+    /// Dev account the node authors blocks as (`alice` runs it with `--alice`)
+    #[clap(long, default_value = "alice")]
+    pub authority: String,
+    /// Port of the node's RPC server
+    #[clap(long, default_value_t = 9944)]
+    pub rpc_port: u16,
+    /// Extra arguments for the node (e.g. `--node-arg=--pruning=archive`)
+    #[clap(long = "node-arg", allow_hyphen_values = true)]
+    pub node_args: Option<Vec<String>>,
+    /// Seconds to wait for the node before failing
+    #[clap(long, default_value_t = 300)]
+    pub timeout: u64,
+}
+
+#[derive(clap::Args)]
+pub struct RunArgs {
+    /// The fork's chain-spec
+    #[clap(default_value = "fork.json")]
+    pub spec: PathBuf,
+    #[clap(flatten)]
+    pub node: NodeArgs,
+    /// Blocks that must be finalized for the fork to pass
+    #[clap(long, default_value_t = 5)]
+    pub blocks: u32,
+    /// Keep the node running once the checks are done, until Ctrl-C
+    #[clap(long)]
+    pub keep_running: bool,
+}
+
+#[derive(clap::Args)]
+pub struct VerifyArgs {
+    /// The fork's chain-spec
+    pub spec: PathBuf,
+    /// The original chain's raw chain-spec, to check the fork's genesis
+    /// differs from it
+    #[clap(long)]
+    pub original: Option<PathBuf>,
+    /// Pallets (`Pallet`), storage items (`Pallet.Item`) or hex key prefixes
+    /// that must have no keys in the fork
+    #[clap(long, value_delimiter = ',')]
+    pub excluded: Option<Vec<String>>,
+    /// Don't require `Sudo.Key` to be set
+    #[clap(long)]
+    pub no_sudo: bool,
+}
+
+#[derive(clap::Args)]
+pub struct UpgradeTestArgs {
+    /// The fork's chain-spec, running the on-chain runtime. With `--config`
+    /// it is built first and written here.
+    #[clap(default_value = "fork.json")]
+    pub spec: PathBuf,
+    /// Fork recipe (as for `--config` when building a fork) to build the
+    /// fork from first, with the on-chain runtime. Its `bin` defaults to
+    /// `--bin`.
+    #[clap(long)]
+    pub config: Option<PathBuf>,
+    #[clap(flatten)]
+    pub node: NodeArgs,
+    /// Path to the runtime WASM blob to upgrade to, checked like a
+    /// `--runtime` blob
+    #[clap(long)]
+    pub wasm: PathBuf,
+    /// Compress a plain wasm `--wasm` blob before submitting it
+    #[clap(long)]
+    pub compress_runtime: bool,
+    /// Largest `--wasm` blob, in bytes
+    #[clap(long, default_value_t = 5 * 1024 * 1024)]
+    pub max_code_size: usize,
+    /// Secret URI of the fork's sudo key, which submits the upgrade
+    #[clap(long, default_value = "//Alice")]
+    pub signer: String,
+    /// Script to run once the upgrade went through, with `FORK_RPC_URL` set
+    /// to the node's RPC endpoint; the test fails if it does
+    #[clap(long)]
+    pub then: Option<PathBuf>,
+    /// Keep the node running once the checks are done, until Ctrl-C
+    #[clap(long)]
+    pub keep_running: bool,
+}
+
+#[derive(clap::Args)]
+pub struct TryUpgradeArgs {
+    /// The fork's cached state (`<out>.storage.json`)
+    #[clap(default_value = "fork.json.storage.json")]
+    pub storage: PathBuf,
+    /// Runtime WASM blob to upgrade to, built with the `try-runtime` feature
+    #[clap(long)]
+    pub runtime: PathBuf,
+    /// Run the pallets' `try_state` checks after the migrations, besides
+    /// their pre- and post-upgrade checks
+    #[clap(long)]
+    pub try_state: bool,
+    /// Extra heap pages for the runtime
+    #[clap(long)]
+    pub heap_pages: Option<u32>,
+    /// Path to the `creditcoin-fork-try-upgrade` helper (default: next to
+    /// this binary, else on `PATH`)
+    #[clap(long)]
+    pub helper: Option<PathBuf>,
+}
+
+impl Cli {
+    /// Parse the command line, filling in anything it leaves unset from the
+    /// `--config` recipe.
+    pub fn load() -> Result<Self> {
+        use clap::{CommandFactory, FromArgMatches};
+
+        let matches = Self::command().get_matches();
+        let mut cli = Self::from_arg_matches(&matches)?;
+        if let Some(path) = &cli.config {
+            Recipe::load(path)?.apply(&mut cli, &matches)?;
+        }
+        Ok(cli)
+    }
+
+    /// The build options of the recipe at `path`, as if run with just
+    /// `--config <path>`.
+    pub fn from_recipe(path: &Path) -> Result<Self> {
+        use clap::{CommandFactory, FromArgMatches};
+
+        let matches = Self::command().try_get_matches_from([
+            OsStr::new("creditcoin-fork"),
+            OsStr::new("--config"),
+            path.as_os_str(),
+        ])?;
+        let mut cli = Self::from_arg_matches(&matches)?;
+        Recipe::load(path)?.apply(&mut cli, &matches)?;
+        Ok(cli)
+    }
 }
 
 impl fmt::Display for Chain {
@@ -0,0 +1,166 @@
+use color_eyre::{eyre::eyre, Result};
+use console::style;
+use frame_metadata::RuntimeMetadataV14;
+use serde_json::{json, Value as JsonValue};
+use sp_core::crypto::{AccountId32, Ss58Codec};
+
+use crate::metadata::{self, put_typed};
+use crate::{storage_prefix, ToHex};
+
+/// How a pallet can hand control of the fork to a single account.
+#[derive(Debug, PartialEq)]
+enum Role {
+    /// `pallet_sudo`: `Key`.
+    Sudo,
+    /// `pallet_collective`: `Members`, with motions (`Proposals`).
+    Collective,
+    /// `pallet_membership`: `Members` and `Prime`, feeding a collective.
+    Membership,
+}
+
+/// Classify a pallet by the storage items it declares.
+fn role(pallet: &str, items: &[&str]) -> Option<Role> {
+    let has = |item| items.contains(&item);
+    if pallet == "Sudo" && has("Key") {
+        Some(Role::Sudo)
+    } else if has("Members") && has("Proposals") && has("ProposalCount") {
+        Some(Role::Collective)
+    } else if has("Members") && has("Prime") {
+        Some(Role::Membership)
+    } else {
+        None
+    }
+}
+
+/// The pallets [`grant_control`] handed to the account.
+pub struct Control {
+    pub pallets: Vec<String>,
+    /// Prefixes of the collectives' pending motions (`Proposals`,
+    /// `ProposalOf`, `Voting`), to be dropped: their votes and thresholds are
+    /// the original members', which the new sole member can't close.
+    pub stale_prefixes: Vec<String>,
+}
+
+/// Set `Sudo.Key` to `account` without the metadata, for runtimes the base
+/// spec shows to have the Sudo pallet (it holds a bare `AccountId32`).
+pub fn set_sudo_key(
+    account: &[u8; 32],
+    overrides: &mut serde_json::Map<String, JsonValue>,
+) -> Control {
+    overrides.insert(storage_prefix("Sudo", "Key"), account.to_hex().into());
+    println!(
+        "Sudo key set to {}",
+        AccountId32::from(*account).to_ss58check()
+    );
+    Control {
+        pallets: vec!["Sudo".to_owned()],
+        stale_prefixes: Vec::new(),
+    }
+}
+
+/// Make `account` the fork's governance: its `Sudo.Key` when the runtime has
+/// the Sudo pallet, otherwise the sole (and prime) member of every collective
+/// and membership pallet.
+pub fn grant_control(
+    meta: &RuntimeMetadataV14,
+    account: &[u8; 32],
+    overrides: &mut serde_json::Map<String, JsonValue>,
+) -> Result<Control> {
+    let id = JsonValue::from(account.to_hex());
+    let ss58 = AccountId32::from(*account).to_ss58check();
+
+    let roles: Vec<(&str, Role)> = meta
+        .pallets
+        .iter()
+        .filter_map(|pallet| {
+            let storage = pallet.storage.as_ref()?;
+            let items: Vec<&str> = storage.entries.iter().map(|e| e.name.as_str()).collect();
+            role(&pallet.name, &items).map(|r| (pallet.name.as_str(), r))
+        })
+        .collect();
+
+    let mut put = |pallet: &str, item: &str, value: &JsonValue| -> Result<()> {
+        if let Some(found) = metadata::find_item(meta, pallet, item) {
+            put_typed(meta, overrides, &found, &[], value)
+                .map_err(|e| eyre!("{pallet}.{item}: {e}"))?;
+        }
+        Ok(())
+    };
+
+    if roles.iter().any(|(_, r)| *r == Role::Sudo) {
+        put("Sudo", "Key", &id)?;
+        println!("Sudo key set to {ss58}");
+        return Ok(Control {
+            pallets: vec!["Sudo".to_owned()],
+            stale_prefixes: Vec::new(),
+        });
+    }
+
+    let mut granted = Vec::new();
+    let mut stale_prefixes = Vec::new();
+    for (pallet, role) in &roles {
+        put(*pallet, "Members", &json!([id]))?;
+        put(*pallet, "Prime", &id)?;
+        granted.push((*pallet).to_owned());
+        if *role == Role::Collective {
+            stale_prefixes.extend(
+                ["Proposals", "ProposalOf", "Voting"].map(|item| storage_prefix(pallet, item)),
+            );
+        }
+    }
+    if granted.is_empty() {
+        println!(
+            "{}",
+            style("warning: the runtime has no Sudo, collective or membership pallet; no governance account was set").yellow()
+        );
+    } else {
+        println!(
+            "The runtime has no Sudo pallet; {ss58} is the sole member of {} (pending motions dropped)",
+            granted.join(", ")
+        );
+    }
+    Ok(Control {
+        pallets: granted,
+        stale_prefixes,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pallets_are_classified_by_their_storage() {
+        assert_eq!(role("Sudo", &["Key"]), Some(Role::Sudo));
+        assert_eq!(
+            role(
+                "Council",
+                &[
+                    "Proposals",
+                    "ProposalOf",
+                    "Voting",
+                    "ProposalCount",
+                    "Members",
+                    "Prime"
+                ]
+            ),
+            Some(Role::Collective)
+        );
+        assert_eq!(
+            role("TechnicalMembership", &["Members", "Prime"]),
+            Some(Role::Membership)
+        );
+        assert_eq!(role("Balances", &["TotalIssuance", "Account"]), None);
+    }
+
+    #[test]
+    fn the_sudo_key_is_set_without_metadata() {
+        let mut overrides = serde_json::Map::new();
+        let control = set_sudo_key(&[7; 32], &mut overrides);
+        assert_eq!(control.pallets, ["Sudo"]);
+        assert_eq!(
+            overrides[&storage_prefix("Sudo", "Key")],
+            [7u8; 32].to_hex()
+        );
+    }
+}
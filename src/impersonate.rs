@@ -0,0 +1,324 @@
+use std::collections::HashMap;
+
+use codec::Encode;
+use color_eyre::{eyre::eyre, Result};
+use frame_metadata::{RuntimeMetadataV14, StorageHasher};
+use scale_info::{form::PortableForm, Type};
+use sp_core::crypto::{AccountId32, Ss58Codec};
+
+use crate::accounts::parse_account;
+use crate::cli::Cli;
+use crate::metadata::{append_hashed, StorageItem};
+use crate::scale::{self, contains_type};
+use crate::{storage_prefix, ToHex};
+
+/// A mainnet account whose state is moved onto a key we hold.
+#[derive(Debug, PartialEq)]
+pub struct Impersonation {
+    pub source: [u8; 32],
+    pub target: [u8; 32],
+}
+
+/// `<account>=<secret URI>`: the account to impersonate and the key to act
+/// as it with.
+fn parse_impersonation(s: &str) -> Result<Impersonation> {
+    let (source, target) = s
+        .split_once('=')
+        .ok_or_else(|| eyre!("expected `<account>=<secret URI>`, got `{s}`"))?;
+    let impersonation = Impersonation {
+        source: parse_account(source)?,
+        target: parse_account(target)?,
+    };
+    if impersonation.source == impersonation.target {
+        return Err(eyre!("`{s}` impersonates an account with itself"));
+    }
+    Ok(impersonation)
+}
+
+pub fn impersonations_from_cli(cli: &Cli) -> Result<Vec<Impersonation>> {
+    let impersonations = cli
+        .impersonate
+        .iter()
+        .flatten()
+        .map(|s| parse_impersonation(s).map_err(|e| eyre!("invalid --impersonate: {e}")))
+        .collect::<Result<Vec<_>>>()?;
+    for (i, a) in impersonations.iter().enumerate() {
+        let clash = impersonations[..i].iter().any(|b| {
+            [a.source, a.target]
+                .iter()
+                .any(|x| *x == b.source || *x == b.target)
+        });
+        if clash {
+            return Err(eyre!(
+                "--impersonate: {} appears in more than one impersonation",
+                AccountId32::from(a.source).to_ss58check()
+            ));
+        }
+    }
+    Ok(impersonations)
+}
+
+fn is_account_id(ty: &Type<PortableForm>) -> bool {
+    ty.path().segments().last().map(String::as_str) == Some("AccountId32")
+}
+
+/// A storage item that can refer to an account: in its key (through the
+/// hashers of its `AccountId32` key components) or in its values.
+struct AccountItem {
+    prefix: Vec<u8>,
+    account_hashers: Vec<StorageHasher>,
+    value_has_account: bool,
+}
+
+/// Moves impersonated accounts' state onto their targets: entries keyed by a
+/// source account are re-keyed to its target, and source account ids inside
+/// values (of types that contain account ids) are rewritten.
+pub struct Transplant<'a> {
+    items: Vec<AccountItem>,
+    impersonations: &'a [Impersonation],
+}
+
+impl<'a> Transplant<'a> {
+    pub fn new(meta: &RuntimeMetadataV14, impersonations: &'a [Impersonation]) -> Result<Self> {
+        let mut items = Vec::new();
+        for pallet in &meta.pallets {
+            let Some(storage) = &pallet.storage else {
+                continue;
+            };
+            for entry in &storage.entries {
+                let item = StorageItem {
+                    pallet_prefix: &storage.prefix,
+                    entry,
+                };
+                let account_hashers: Vec<StorageHasher> = item
+                    .key_components(&meta.types)?
+                    .into_iter()
+                    .filter(|(_, ty)| scale::resolve(&meta.types, *ty).is_ok_and(is_account_id))
+                    .map(|(hasher, _)| hasher.clone())
+                    .collect();
+                let value_has_account =
+                    contains_type(&meta.types, item.value_type(), &is_account_id);
+                if !account_hashers.is_empty() || value_has_account {
+                    items.push(AccountItem {
+                        prefix: hex::decode(&storage_prefix(&storage.prefix, &entry.name)[2..])
+                            .expect("prefix is valid hex"),
+                        account_hashers,
+                        value_has_account,
+                    });
+                }
+            }
+        }
+        Ok(Self {
+            items,
+            impersonations,
+        })
+    }
+
+    fn item(&self, key: &[u8]) -> Option<&AccountItem> {
+        self.items.iter().find(|item| key.starts_with(&item.prefix))
+    }
+
+    /// Whether the entry at `key` may refer to an impersonated account.
+    pub fn covers(&self, key: &str) -> bool {
+        key.strip_prefix("0x")
+            .and_then(|digits| hex::decode(digits.get(..64)?).ok())
+            .is_some_and(|prefix| self.item(&prefix).is_some())
+    }
+
+    /// The entry with impersonated accounts replaced by their targets, or
+    /// `None` if it doesn't refer to any of them.
+    pub fn rewrite(&self, key: &str, value: &str) -> Option<(String, String)> {
+        let mut key = hex::decode(key.strip_prefix("0x")?).ok()?;
+        let mut value = hex::decode(value.strip_prefix("0x")?).ok()?;
+        let item = self.item(&key)?;
+        let mut changed = false;
+        for imp in self.impersonations {
+            for hasher in &item.account_hashers {
+                let hashed = |account: &[u8; 32]| {
+                    let mut out = Vec::new();
+                    append_hashed(&mut out, hasher, &account.encode());
+                    out
+                };
+                let prefix_len = item.prefix.len();
+                changed |= replace_all(
+                    &mut key[prefix_len..],
+                    &hashed(&imp.source),
+                    &hashed(&imp.target),
+                );
+            }
+            if item.value_has_account {
+                changed |= replace_all(&mut value, &imp.source, &imp.target);
+            }
+        }
+        changed.then(|| (key.to_hex(), value.to_hex()))
+    }
+}
+
+/// Reconcile the entries moved onto impersonation targets with the targets'
+/// own state (`existing`, by key), returning how many accounts were merged. A
+/// target's `System.Account` is merged with the moved one (see
+/// [`merge_accounts`]), so no balance is created or destroyed and
+/// `Balances.TotalIssuance` still holds; any other entry a target already has
+/// is an error, as it would be overwritten by or mixed with the moved state.
+pub fn merge_target_state(
+    moved: &mut [(String, String)],
+    existing: &HashMap<String, String>,
+) -> Result<usize> {
+    let account_prefix = storage_prefix("System", "Account");
+    let bytes = |value: &str| hex::decode(value.trim_start_matches("0x")).ok();
+    let mut clashes = Vec::new();
+    let mut merged = 0;
+    for (key, value) in moved.iter_mut() {
+        let Some(own) = existing.get(key.as_str()) else {
+            continue;
+        };
+        if !key.starts_with(&account_prefix) {
+            clashes.push(key.clone());
+            continue;
+        }
+        *value = bytes(value.as_str())
+            .zip(bytes(own))
+            .and_then(|(moved, own)| merge_accounts(&moved, &own))
+            .ok_or_else(|| {
+                eyre!("--impersonate: can't merge the account at {key} with the one moved onto it")
+            })?
+            .to_hex();
+        merged += 1;
+    }
+    if let Some(first) = clashes.first() {
+        return Err(eyre!(
+            "--impersonate: {} entries moved onto a target already exist on the fork (first: {first}); impersonate with a key that has no state of its own",
+            clashes.len()
+        ));
+    }
+    Ok(merged)
+}
+
+/// An `AccountInfo` holding both accounts: their reference counts and
+/// balances added up, with the moved account's nonce and flags.
+pub fn merge_accounts(moved: &[u8], own: &[u8]) -> Option<Vec<u8>> {
+    if moved.len() < 64 || own.len() < 64 {
+        return None;
+    }
+    let mut out = moved.to_vec();
+    for range in [4..8, 8..12, 12..16] {
+        let sum = u32::from_le_bytes(moved[range.clone()].try_into().ok()?)
+            .saturating_add(u32::from_le_bytes(own[range.clone()].try_into().ok()?));
+        out[range].copy_from_slice(&sum.to_le_bytes());
+    }
+    for range in [16..32, 32..48, 48..64] {
+        let sum = u128::from_le_bytes(moved[range.clone()].try_into().ok()?)
+            .checked_add(u128::from_le_bytes(own[range.clone()].try_into().ok()?))?;
+        out[range].copy_from_slice(&sum.to_le_bytes());
+    }
+    Some(out)
+}
+
+/// Replace every non-overlapping occurrence of `from` in `bytes` with the
+/// same-length `to`.
+fn replace_all(bytes: &mut [u8], from: &[u8], to: &[u8]) -> bool {
+    debug_assert_eq!(from.len(), to.len());
+    let mut replaced = false;
+    let mut i = 0;
+    while i + from.len() <= bytes.len() {
+        if &bytes[i..i + from.len()] == from {
+            bytes[i..i + from.len()].copy_from_slice(to);
+            replaced = true;
+            i += from.len();
+        } else {
+            i += 1;
+        }
+    }
+    replaced
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn impersonations_parse_and_reject_self_targets() {
+        let alice = parse_account("//Alice").unwrap();
+        let bob = parse_account("//Bob").unwrap();
+        let imp =
+            parse_impersonation("5GrwvaEF5zXb26Fz9rcQpDWS57CtERHpNehXCPcNoHGKutQY=//Bob").unwrap();
+        assert_eq!(
+            imp,
+            Impersonation {
+                source: alice,
+                target: bob
+            }
+        );
+        assert!(parse_impersonation("//Alice=//Alice").is_err());
+        assert!(parse_impersonation("//Alice").is_err());
+    }
+
+    #[test]
+    fn replaces_every_occurrence() {
+        let mut bytes = [1, 2, 3, 1, 2, 1, 2];
+        assert!(replace_all(&mut bytes, &[1, 2], &[9, 9]));
+        assert_eq!(bytes, [9, 9, 3, 9, 9, 9, 9]);
+        assert!(!replace_all(&mut bytes, &[1, 2], &[9, 9]));
+    }
+
+    #[test]
+    fn rewrites_keys_and_embedded_accounts() {
+        let alice = parse_account("//Alice").unwrap();
+        let bob = parse_account("//Bob").unwrap();
+        let impersonations = [Impersonation {
+            source: alice,
+            target: bob,
+        }];
+        let prefix = hex::decode(&storage_prefix("Staking", "Ledger")[2..]).unwrap();
+        let transplant = Transplant {
+            items: vec![AccountItem {
+                prefix: prefix.clone(),
+                account_hashers: vec![StorageHasher::Blake2_128Concat],
+                value_has_account: true,
+            }],
+            impersonations: &impersonations,
+        };
+        let key_for = |account: &[u8; 32]| {
+            let mut key = prefix.clone();
+            append_hashed(&mut key, &StorageHasher::Blake2_128Concat, account);
+            key.to_hex()
+        };
+        let value = [&alice[..], &[7u8; 4]].concat().to_hex();
+
+        assert!(transplant.covers(&key_for(&alice)));
+        let (key, rewritten) = transplant.rewrite(&key_for(&alice), &value).unwrap();
+        assert_eq!(key, key_for(&bob));
+        assert_eq!(rewritten, [&bob[..], &[7u8; 4]].concat().to_hex());
+        let charlie = parse_account("//Charlie").unwrap();
+        assert!(transplant.rewrite(&key_for(&charlie), "0x00").is_none());
+    }
+
+    #[test]
+    fn targets_accounts_are_merged_and_other_state_is_rejected() {
+        let account = |nonce: u32, providers: u32, free: u128, reserved: u128| {
+            let mut info = nonce.to_le_bytes().to_vec();
+            info.extend([0, 0, 0, 0]);
+            info.extend(providers.to_le_bytes());
+            info.extend([0, 0, 0, 0]);
+            info.extend(free.to_le_bytes());
+            info.extend(reserved.to_le_bytes());
+            info.extend(0u128.to_le_bytes());
+            info.extend(0u128.to_le_bytes());
+            info
+        };
+        let account_key = format!("{}00", storage_prefix("System", "Account"));
+        let ledger_key = format!("{}00", storage_prefix("Staking", "Ledger"));
+        let mut moved = vec![
+            (account_key.clone(), account(5, 1, 100, 7).to_hex()),
+            ("0x01".to_owned(), "0x02".to_owned()),
+        ];
+        let existing = HashMap::from([(account_key.clone(), account(9, 1, 20, 0).to_hex())]);
+        assert_eq!(merge_target_state(&mut moved, &existing).unwrap(), 1);
+        assert_eq!(moved[0].1, account(5, 2, 120, 7).to_hex());
+        assert_eq!(moved[1].1, "0x02");
+
+        let mut moved = vec![(ledger_key.clone(), "0x00".to_owned())];
+        let existing = HashMap::from([(ledger_key, "0x01".to_owned())]);
+        assert!(merge_target_state(&mut moved, &existing).is_err());
+    }
+}
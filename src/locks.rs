@@ -0,0 +1,404 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
+
+use color_eyre::{eyre::eyre, Result};
+use console::style;
+use frame_metadata::RuntimeMetadataV14;
+use serde_json::Value as JsonValue;
+
+use crate::metadata;
+use crate::scale::{decode_value, encode_json, json_u128};
+use crate::stakers::trailing_account;
+use crate::{
+    json_hex_bytes, read_matching_entries, read_selected_keys, storage_prefix,
+    system_account_storage_key, ToHex, TopFilter,
+};
+
+/// The pallet owning a balance lock, by its `LockIdentifier`.
+fn lock_owner(id: &[u8]) -> Option<&'static str> {
+    match id {
+        b"staking " => Some("Staking"),
+        b"vesting " => Some("Vesting"),
+        b"pyconvot" => Some("ConvictionVoting"),
+        b"democrac" => Some("Democracy"),
+        b"phrelect" => Some("Elections"),
+        _ => None,
+    }
+}
+
+/// A freeze or hold reason's pallet: the outer variant of the runtime's
+/// `RuntimeFreezeReason`/`RuntimeHoldReason` (`{"Pallet": "Reason"}`).
+fn reason_owner(id: &JsonValue) -> Option<&str> {
+    match id {
+        JsonValue::String(pallet) => Some(pallet),
+        JsonValue::Object(map) if map.len() == 1 => map.keys().next().map(String::as_str),
+        _ => None,
+    }
+}
+
+fn lock_label(id: &[u8]) -> String {
+    match std::str::from_utf8(id) {
+        Ok(s) if s.chars().all(|c| c.is_ascii_graphic() || c == ' ') => format!("`{s}`"),
+        _ => id.to_hex(),
+    }
+}
+
+/// The account-keyed storage item recording what a lock owner locks an
+/// account's funds for.
+fn owner_item(pallet: &str) -> Option<&'static str> {
+    match pallet {
+        "Vesting" => Some("Vesting"),
+        "ConvictionVoting" => Some("VotingFor"),
+        "Democracy" => Some("VotingOf"),
+        "Elections" => Some("Voting"),
+        _ => None,
+    }
+}
+
+/// The fork's stakers: `Staking.Bonded` (stash to controller) and the
+/// controllers `Staking.Ledger` has a ledger for.
+#[derive(Default)]
+struct Stakers {
+    bonded_prefix: String,
+    ledger_prefix: String,
+    controllers: HashMap<[u8; 32], [u8; 32]>,
+    ledgers: HashSet<[u8; 32]>,
+}
+
+impl Stakers {
+    fn new() -> Self {
+        Self {
+            bonded_prefix: storage_prefix("Staking", "Bonded"),
+            ledger_prefix: storage_prefix("Staking", "Ledger"),
+            ..Self::default()
+        }
+    }
+
+    fn is_staking_key(&self, key: &str) -> bool {
+        key.starts_with(&self.bonded_prefix) || key.starts_with(&self.ledger_prefix)
+    }
+
+    fn note(&mut self, key: &str, value: &str) {
+        if key.starts_with(&self.bonded_prefix) {
+            let controller = hex::decode(value.trim_start_matches("0x"))
+                .ok()
+                .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok());
+            if let (Some(stash), Some(controller)) = (trailing_account(key), controller) {
+                self.controllers.insert(stash, controller);
+            }
+        } else if key.starts_with(&self.ledger_prefix) {
+            self.ledgers.extend(trailing_account(key));
+        }
+    }
+
+    /// Whether `stash` has a ledger, which is keyed by its controller.
+    fn staked(&self, stash: &[u8; 32]) -> bool {
+        self.ledgers
+            .contains(self.controllers.get(stash).unwrap_or(stash))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Lock,
+    Freeze,
+    Hold,
+}
+
+/// How an account's balance changes when its orphaned entries are removed.
+#[derive(Default)]
+struct AccountChange {
+    /// Largest remaining lock and freeze amounts, for `frozen`.
+    max_lock: u128,
+    max_freeze: u128,
+    /// Held balance returned from `reserved` to `free`.
+    released: u128,
+    /// Whether all of the account's locks were removed.
+    locks_emptied: bool,
+    changed: bool,
+}
+
+/// Find balance locks, freezes and holds whose owning pallet's state the fork
+/// doesn't keep: staking locks and holds of accounts without a staking ledger
+/// on the fork, and other known locks, freezes and holds of pallets whose
+/// storage `filter` drops. Report them and, with `remove`, drop them and fix
+/// up `System.Account` (`frozen`, released holds and consumer references).
+pub fn check_orphaned_locks(
+    meta: &RuntimeMetadataV14,
+    storage_path: Option<&Path>,
+    base_top: &serde_json::Map<String, JsonValue>,
+    filter: &mut TopFilter,
+    overrides: &mut serde_json::Map<String, JsonValue>,
+    remove: bool,
+) -> Result<()> {
+    let items: Vec<(Kind, &str, metadata::StorageItem)> = [
+        (Kind::Lock, "Locks"),
+        (Kind::Freeze, "Freezes"),
+        (Kind::Hold, "Holds"),
+    ]
+    .into_iter()
+    .filter_map(|(kind, item)| {
+        metadata::find_item(meta, "Balances", item).map(|found| (kind, item, found))
+    })
+    .collect();
+    let prefixes: Vec<String> = items.iter().map(|(_, _, item)| item.prefix()).collect();
+
+    // The fork's lock entries: overrides win over the kept storage entries.
+    let mut entries: Vec<(String, String)> = match storage_path {
+        Some(path) => read_matching_entries(path, |k, _| {
+            prefixes.iter().any(|p| k.starts_with(p.as_str()))
+                && filter.keeps_storage_key(k)
+                && !overrides.contains_key(k)
+        })?,
+        None => Vec::new(),
+    };
+    entries.extend(
+        overrides
+            .iter()
+            .filter(|(k, _)| prefixes.iter().any(|p| k.starts_with(p.as_str())))
+            .filter_map(|(k, v)| Some((k.clone(), v.as_str()?.to_owned()))),
+    );
+
+    // The fork's staking ledgers: overrides win over the kept storage entries.
+    let mut stakers = Stakers::new();
+    if let Some(path) = storage_path {
+        read_matching_entries(path, |k, v| {
+            if stakers.is_staking_key(k)
+                && filter.keeps_storage_key(k)
+                && !overrides.contains_key(k)
+            {
+                stakers.note(k, v);
+            }
+            false
+        })?;
+    }
+    for (k, v) in overrides.iter() {
+        if let Some(v) = v.as_str().filter(|_| stakers.is_staking_key(k)) {
+            stakers.note(k, v);
+        }
+    }
+
+    // Whether the fork keeps the owning pallet's record of the account: its
+    // entry of the owner's account-keyed item if known, else any of the
+    // pallet's items (`--include-items` may keep only some).
+    let owner_kept = |pallet: &str, account: &[u8; 32]| {
+        if let Some(item) = owner_item(pallet).and_then(|i| metadata::find_item(meta, pallet, i)) {
+            let key = item
+                .key(&meta.types, &[account.to_hex().into()])
+                .unwrap_or_else(|_| item.prefix());
+            return filter.keeps_storage_key(&key);
+        }
+        meta.pallets
+            .iter()
+            .find(|p| p.name == pallet)
+            .and_then(|p| p.storage.as_ref())
+            .is_some_and(|s| {
+                s.entries
+                    .iter()
+                    .any(|e| filter.keeps_storage_key(&storage_prefix(&s.prefix, &e.name)))
+            })
+    };
+    let orphaned = |owner: &str, account: &[u8; 32]| {
+        if owner == "Staking" {
+            !stakers.staked(account)
+        } else {
+            !owner_kept(owner, account)
+        }
+    };
+
+    // Orphans per label: (accounts, total amount).
+    let mut report: BTreeMap<String, (usize, u128)> = BTreeMap::new();
+    let mut changes: HashMap<[u8; 32], AccountChange> = HashMap::new();
+    let mut rewritten: Vec<(String, Option<String>)> = Vec::new();
+    for (key, value) in &entries {
+        let Some((kind, item_name, item)) = items
+            .iter()
+            .find(|(_, _, item)| key.starts_with(&item.prefix()))
+        else {
+            continue;
+        };
+        let Some(account) = trailing_account(key) else {
+            continue;
+        };
+        let bytes = hex::decode(value.trim_start_matches("0x"))?;
+        let list = decode_value(&meta.types, item.value_type(), &bytes)
+            .map_err(|e| eyre!("Balances.{item_name}: {e}"))?;
+        let list = list
+            .as_array()
+            .ok_or_else(|| eyre!("Balances.{item_name}: expected a list"))?;
+
+        let mut kept = Vec::with_capacity(list.len());
+        let change = changes.entry(account).or_default();
+        for entry in list {
+            let amount = json_u128(&entry["amount"])?;
+            let (label, owner) = if *kind == Kind::Lock {
+                let id = json_hex_bytes(&entry["id"]).unwrap_or_default();
+                (lock_label(&id), lock_owner(&id).map(str::to_owned))
+            } else {
+                (
+                    format!("{item_name} {}", entry["id"]),
+                    reason_owner(&entry["id"]).map(str::to_owned),
+                )
+            };
+            if owner.is_some_and(|owner| orphaned(&owner, &account)) {
+                let (accounts, total) = report.entry(label).or_default();
+                *accounts += 1;
+                *total = total.saturating_add(amount);
+                if remove {
+                    if *kind == Kind::Hold {
+                        change.released = change.released.saturating_add(amount);
+                    }
+                    continue;
+                }
+            }
+            match kind {
+                Kind::Lock => change.max_lock = change.max_lock.max(amount),
+                Kind::Freeze => change.max_freeze = change.max_freeze.max(amount),
+                Kind::Hold => {}
+            }
+            kept.push(entry.clone());
+        }
+        if kept.len() != list.len() {
+            change.changed = true;
+            if *kind == Kind::Lock && kept.is_empty() {
+                change.locks_emptied = true;
+            }
+            let new_value = if kept.is_empty() {
+                None
+            } else {
+                let mut encoded = Vec::new();
+                encode_json(
+                    &meta.types,
+                    item.value_type(),
+                    &JsonValue::Array(kept),
+                    &mut encoded,
+                )?;
+                Some(encoded.to_hex())
+            };
+            rewritten.push((key.clone(), new_value));
+        }
+    }
+
+    if report.is_empty() {
+        println!("No orphaned balance locks, freezes or holds");
+        return Ok(());
+    }
+    for (label, (accounts, total)) in &report {
+        println!(
+            "{}",
+            style(format!(
+                "Orphaned {label} on {accounts} account(s), {total} planck in total"
+            ))
+            .yellow()
+        );
+    }
+    if !remove {
+        return Ok(());
+    }
+
+    for (key, value) in rewritten {
+        match value {
+            Some(value) => {
+                overrides.insert(key, value.into());
+            }
+            None => {
+                overrides.remove(&key);
+                filter.remove_exact.insert(key);
+            }
+        }
+    }
+
+    // `frozen` is the largest remaining lock or freeze; released holds move
+    // from `reserved` back to `free`. An account drops the consumer reference
+    // of its locks once it has none, and of its balance once nothing is
+    // reserved or frozen any more.
+    let changed: Vec<([u8; 32], &AccountChange)> = changes
+        .iter()
+        .filter(|(_, c)| c.changed)
+        .map(|(a, c)| (*a, c))
+        .collect();
+    let account_keys: HashSet<String> = changed
+        .iter()
+        .map(|(a, _)| system_account_storage_key(a))
+        .collect();
+    let stored = match storage_path {
+        Some(path) => read_selected_keys(path, &account_keys)?,
+        None => HashMap::new(),
+    };
+    for (account, change) in &changed {
+        let key = system_account_storage_key(account);
+        let info = overrides
+            .get(&key)
+            .and_then(json_hex_bytes)
+            .or_else(|| {
+                stored
+                    .get(&key)
+                    .filter(|_| filter.keeps_storage_key(&key))
+                    .and_then(|v| hex::decode(v.trim_start_matches("0x")).ok())
+            })
+            .or_else(|| base_top.get(&key).and_then(json_hex_bytes));
+        let Some(mut info) = info.filter(|i| i.len() >= 64) else {
+            continue;
+        };
+        let u128_at = |info: &[u8], at: usize| {
+            u128::from_le_bytes(info[at..at + 16].try_into().expect("16 bytes"))
+        };
+        let mut consumers = u32::from_le_bytes(info[4..8].try_into().expect("4 bytes"));
+        let (free, reserved, frozen) = (u128_at(&info, 16), u128_at(&info, 32), u128_at(&info, 48));
+        let reserved_after = reserved.saturating_sub(change.released);
+        let frozen_after = change.max_lock.max(change.max_freeze);
+        if change.locks_emptied {
+            consumers = consumers.saturating_sub(1);
+        }
+        if (reserved > 0 || frozen > 0) && reserved_after == 0 && frozen_after == 0 {
+            consumers = consumers.saturating_sub(1);
+        }
+        info[4..8].copy_from_slice(&consumers.to_le_bytes());
+        info[16..32].copy_from_slice(&free.saturating_add(reserved - reserved_after).to_le_bytes());
+        info[32..48].copy_from_slice(&reserved_after.to_le_bytes());
+        info[48..64].copy_from_slice(&frozen_after.to_le_bytes());
+        overrides.insert(key, info.to_hex().into());
+    }
+    println!(
+        "{}",
+        style(format!(
+            "Removed orphaned locks, freezes and holds from {} account(s)",
+            changed.len()
+        ))
+        .green()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{staking_bonded_key, staking_ledger_key};
+
+    #[test]
+    fn owners_come_from_lock_ids_and_reason_variants() {
+        assert_eq!(lock_owner(b"staking "), Some("Staking"));
+        assert_eq!(lock_owner(b"unknown!"), None);
+        assert_eq!(
+            reason_owner(&json!({"NominationPools": "PoolMinBalance"})),
+            Some("NominationPools")
+        );
+        assert_eq!(reason_owner(&json!("Preimage")), Some("Preimage"));
+        assert_eq!(lock_label(b"staking "), "`staking `");
+        assert_eq!(lock_label(&[0, 1]), "0x0001");
+    }
+
+    #[test]
+    fn ledgers_are_found_through_the_controller() {
+        let stash = [1u8; 32];
+        let controller = [2u8; 32];
+        let mut stakers = Stakers::new();
+        stakers.note(&staking_bonded_key(&stash), &controller.to_hex());
+        assert!(!stakers.staked(&stash));
+        stakers.note(&staking_ledger_key(&controller), "0x00");
+        assert!(stakers.staked(&stash));
+        assert!(!stakers.staked(&[3u8; 32]));
+    }
+}
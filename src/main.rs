@@ -1,20 +1,40 @@
+mod accounts;
+mod chain_spec;
 mod cli;
+mod governance;
+mod impersonate;
+mod locks;
+mod metadata;
+mod node;
+mod overrides;
+mod provenance;
+mod recipe;
+mod runtime;
+mod scale;
+mod stakers;
+mod try_upgrade;
+mod unbonding;
+mod upgrade;
+mod validators;
+mod verify;
 
 use std::collections::HashMap;
 use std::ffi::OsStr;
 use std::fmt;
 use std::fs::File;
 use std::io::{BufReader, BufWriter, Write as _};
+use std::marker::PhantomData;
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex, OnceLock};
 use std::{collections::HashSet, fmt::Debug};
 
 use bls_signatures::{PrivateKey as BlsPrivateKey, Serialize as BlsSerialize};
-use clap::Parser;
+use codec::Decode as _;
 use color_eyre::Result;
 use color_eyre::{eyre::eyre, Report};
 use console::style;
 use extend::ext;
+use frame_metadata::{RuntimeMetadata, RuntimeMetadataPrefixed, RuntimeMetadataV14};
 use futures::{StreamExt, TryStreamExt};
 use jsonrpsee::client_transport::ws::{Receiver, Sender, Uri, WsTransportClientBuilder};
 use jsonrpsee::core::client::ClientT;
@@ -31,7 +51,8 @@ use subxt::tx::{BaseExtrinsicParams, PlainTip};
 use subxt::{OnlineClient, SubstrateConfig};
 use tokio::process::Command;
 
-use crate::cli::StorageFile;
+use crate::cli::{BlockRef, StorageFile};
+use crate::provenance::{Provenance, RuntimeSource, StateRecord};
 
 pub type ExtrinsicParams = BaseExtrinsicParams<SubstrateConfig, PlainTip>;
 
@@ -66,7 +87,7 @@ struct ChainSpec {
     id: String,
     chain_type: String,
     boot_nodes: Vec<String>,
-    telemetry_endpoints: Option<Vec<String>>,
+    telemetry_endpoints: Option<Vec<(String, u8)>>,
     protocol_id: Option<String>,
     properties: Option<JsonValue>,
     code_substitutes: JsonValue,
@@ -102,7 +123,7 @@ where
     }
 }
 
-#[ext]
+#[ext(name = ToHex)]
 impl<Slice: AsRef<[u8]>> Slice {
     fn to_hex(&self) -> String {
         let mut s = String::from("0x");
@@ -121,6 +142,9 @@ const VALUE_BATCH_CONCURRENCY: usize = 32;
 /// Per-key concurrency within a batch that fell back to single fetches.
 const FALLBACK_FETCH_CONCURRENCY: usize = 32;
 
+/// Requests sent to an HTTP endpoint to check it has the state to fetch.
+const PREFLIGHT_HTTP_PROBES: usize = 4;
+
 /// A raw JSON-RPC client for the bulk state fetch: either an HTTP(S) client
 /// (preferred — load balancers spread stateless requests across backends,
 /// where a websocket session is pinned to one backend's rate limit) or a
@@ -157,10 +181,44 @@ impl RawClient {
         self.request("state_getStorage", rpc_params![key, at]).await
     }
 
-    /// `chain_getBlockHash` of the latest block.
-    async fn latest_block_hash(&self) -> Result<String> {
+    /// `chain_getBlockHash` of block `number`.
+    async fn block_hash(&self, number: u32) -> Result<String> {
+        let hash: Option<String> = self
+            .request("chain_getBlockHash", rpc_params![number])
+            .await?;
+        hash.ok_or_else(|| eyre!("block #{number} not found"))
+    }
+
+    /// `chain_getBlockHash` of the best block.
+    async fn best_block_hash(&self) -> Result<String> {
         let hash: Option<String> = self.request("chain_getBlockHash", rpc_params![]).await?;
-        hash.ok_or_else(|| eyre!("failed to get latest block hash"))
+        hash.ok_or_else(|| eyre!("failed to get the best block hash"))
+    }
+
+    /// `chain_getFinalizedHead`.
+    async fn finalized_hash(&self) -> Result<String> {
+        self.request("chain_getFinalizedHead", rpc_params![]).await
+    }
+
+    /// `state_getMetadata` at block `at`.
+    async fn metadata(&self, at: &str) -> Result<RuntimeMetadataV14> {
+        let bytes: String = self.request("state_getMetadata", rpc_params![at]).await?;
+        let bytes = hex::decode(bytes.trim_start_matches("0x"))?;
+        match RuntimeMetadataPrefixed::decode(&mut &bytes[..])?.1 {
+            RuntimeMetadata::V14(meta) => Ok(meta),
+            _ => Err(eyre!("the chain's metadata at {at} is not V14")),
+        }
+    }
+
+    /// The number of block `hash`, from `chain_getHeader`.
+    async fn block_number(&self, hash: &str) -> Result<u32> {
+        let header: Option<JsonValue> = self.request("chain_getHeader", rpc_params![hash]).await?;
+        let number = header
+            .as_ref()
+            .and_then(|h| h["number"].as_str())
+            .ok_or_else(|| eyre!("block {hash} not found"))?;
+        u32::from_str_radix(number.trim_start_matches("0x"), 16)
+            .map_err(|e| eyre!("invalid block number {number}: {e}"))
     }
 }
 
@@ -197,6 +255,36 @@ impl NodePool {
     fn get(&self, index: usize) -> &RawClient {
         &self.clients[index % self.clients.len()]
     }
+
+    /// Fail fast, before a long fetch, if an endpoint no longer has the state
+    /// at block `at` (a non-archive node prunes old state) by reading `:code`
+    /// there from every client. An HTTP endpoint is probed several times to
+    /// reach the backends a load balancer spreads requests over.
+    async fn check_state_available(&self, at: &str) -> Result<()> {
+        let code_key = b":code".to_hex();
+        let probes = self.clients.iter().flat_map(|client| {
+            let probes = match client {
+                RawClient::Http(_) => PREFLIGHT_HTTP_PROBES,
+                RawClient::Ws(_) => 1,
+            };
+            std::iter::repeat(client).take(probes)
+        });
+        let results =
+            futures::future::join_all(probes.map(|client| client.storage_value(&code_key, at)))
+                .await;
+        for result in results {
+            let problem = match result {
+                Ok(Some(_)) => continue,
+                Ok(None) => "it has no runtime code there".to_owned(),
+                Err(e) => e.to_string(),
+            };
+            return Err(eyre!(
+                "the state at block {at} is not available from the RPC endpoint ({problem}); \
+                 it has probably been pruned: use an archive node or a more recent block (--at)"
+            ));
+        }
+        Ok(())
+    }
 }
 
 /// Width (in bytes) at which key ranges are split. Map keys distinguish
@@ -636,10 +724,73 @@ fn read_keys_with_prefix(path: &Path, prefix: &str) -> Result<Vec<String>> {
     KeysWithPrefix { prefix }.deserialize(&mut de).err_into()
 }
 
+/// Stream-deserialize the storage file, collecting what `map` returns for
+/// each entry.
+struct MappedEntries<T, F> {
+    map: F,
+    _out: PhantomData<T>,
+}
+
+impl<'de, T, F: FnMut(&str, &str) -> Option<T>> DeserializeSeed<'de> for MappedEntries<T, F> {
+    type Value = Vec<T>;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de, T, F: FnMut(&str, &str) -> Option<T>> Visitor<'de> for MappedEntries<T, F> {
+    type Value = Vec<T>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "a map of hex storage key-value pairs")
+    }
+
+    fn visit_map<A>(mut self, mut access: A) -> Result<Self::Value, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        let mut out = Vec::new();
+        while let Some(key) = access.next_key::<String>()? {
+            let value = access.next_value::<String>()?;
+            out.extend((self.map)(&key, &value));
+        }
+        Ok(out)
+    }
+}
+
+fn read_mapped_entries<T>(path: &Path, map: impl FnMut(&str, &str) -> Option<T>) -> Result<Vec<T>> {
+    let file = File::open(path)?;
+    let mut de = serde_json::Deserializer::from_reader(BufReader::new(file));
+    MappedEntries {
+        map,
+        _out: PhantomData,
+    }
+    .deserialize(&mut de)
+    .err_into()
+}
+
+/// Stream-deserialize the storage file, collecting the entries `keep` accepts.
+fn read_matching_entries(
+    path: &Path,
+    mut keep: impl FnMut(&str, &str) -> bool,
+) -> Result<Vec<(String, String)>> {
+    read_mapped_entries(path, |key, value| {
+        keep(key, value).then(|| (key.to_owned(), value.to_owned()))
+    })
+}
+
 /// Filters applied while streaming state into the fork's chain-spec.
 struct TopFilter {
     /// Storage entries must match one of these prefixes to be kept.
     include_prefixes: Vec<String>,
+    /// Pallets whose state the fork replaces with its own (the validator
+    /// pallets, unless `--keep-validators`): dropped from storage and the base
+    /// spec alike.
+    replaced_prefixes: Vec<String>,
     /// Entries (from storage or the base spec) matching any of these prefixes are dropped.
     exclude_prefixes: Vec<String>,
     /// Exact keys dropped from storage and the base spec.
@@ -648,6 +799,13 @@ struct TopFilter {
 
 impl TopFilter {
     fn keeps_base_key(&self, key: &str) -> bool {
+        !self.replaced_prefixes.iter().any(|p| key.starts_with(p)) && self.keeps_replaced_key(key)
+    }
+
+    /// Whether an entry of a replaced pallet that the fork carries over anyway
+    /// (e.g. with `--preserve-stakers`) survives the other exclusions and
+    /// removals.
+    fn keeps_replaced_key(&self, key: &str) -> bool {
         !self.exclude_prefixes.iter().any(|p| key.starts_with(p))
             && !self.remove_exact.contains(key)
     }
@@ -767,7 +925,7 @@ struct ChainSpecOut<'a> {
     id: &'a str,
     chain_type: &'a str,
     boot_nodes: &'a [String],
-    telemetry_endpoints: &'a Option<Vec<String>>,
+    telemetry_endpoints: &'a Option<Vec<(String, u8)>>,
     protocol_id: &'a Option<String>,
     properties: &'a Option<JsonValue>,
     code_substitutes: &'a JsonValue,
@@ -978,8 +1136,11 @@ fn pool_id_from_bonded_pools_key(key: &str, bonded_pools_prefix: &str) -> Option
     (raw.len() == 12).then_some(id)
 }
 
-/// 10 CTC in planck (1 CTC = 1e18 planck; matches node `chain_spec` `UNITS`).
-const TEN_CTC_PLANCK: u128 = 10 * 1_000_000_000_000_000_000;
+/// 1 CTC in planck (matches node `chain_spec` `UNITS`).
+const PLANCK_PER_CTC: u128 = 1_000_000_000_000_000_000;
+
+/// 10 CTC in planck.
+const TEN_CTC_PLANCK: u128 = 10 * PLANCK_PER_CTC;
 
 /// `pallet_balances::ExtraFlags::default()` — new balance ref-counting is active.
 const BALANCE_EXTRA_FLAGS: u128 = 0x8000_0000_0000_0000_0000_0000_0000_0000u128;
@@ -1023,11 +1184,6 @@ fn free_balance_from_account_storage(bytes: &[u8]) -> Option<u128> {
     Some(u128::from_le_bytes(arr))
 }
 
-fn free_balance_from_account_json(v: &JsonValue) -> Option<u128> {
-    let b = json_hex_bytes(v)?;
-    free_balance_from_account_storage(&b)
-}
-
 fn scale_u128_storage_hex(n: u128) -> String {
     n.to_le_bytes().to_hex()
 }
@@ -1058,19 +1214,32 @@ async fn build_spec(binary: &Path, chain: Chain) -> Result<ChainSpec> {
     serde_json::from_slice(&out.stdout).err_into()
 }
 
-async fn read_wasm_hex(wasm_path: &Path) -> Result<String> {
+/// Read and validate a runtime blob (see [`runtime::prepare_blob`]).
+async fn read_wasm_hex(wasm_path: &Path, compress: bool, max_code_size: usize) -> Result<String> {
     let wasm = tokio::fs::read(wasm_path).await?;
+    let wasm = runtime::prepare_blob(wasm, compress, max_code_size)
+        .map_err(|e| eyre!("{}: {e}", wasm_path.display()))?;
     let mut wasm_hex = "0x".to_owned();
     wasm_hex.push_str(hex::encode(wasm).trim());
 
     Ok(wasm_hex)
 }
 
-async fn resolve_block_hash(client: &RawClient, at: Option<H256>) -> Result<String> {
-    if let Some(at) = at {
-        Ok(at.0.to_hex())
-    } else {
-        client.latest_block_hash().await
+/// The hash of `block`, resolved once so every request of a fetch is pinned
+/// to the same block even if the chain moves on or reorganizes.
+async fn resolve_block(client: &RawClient, block: &BlockRef) -> Result<String> {
+    match block {
+        BlockRef::Hash(hash) => Ok(hash.0.to_hex()),
+        BlockRef::Number(number) => client.block_hash(*number).await,
+        BlockRef::Finalized => client.finalized_hash().await,
+        BlockRef::Best => client.best_block_hash().await,
+        BlockRef::BeforeFinalized(n) => {
+            let finalized = client.block_number(&client.finalized_hash().await?).await?;
+            let number = finalized
+                .checked_sub(*n)
+                .ok_or_else(|| eyre!("the chain has only {finalized} finalized blocks"))?;
+            client.block_hash(number).await
+        }
     }
 }
 
@@ -1148,8 +1317,9 @@ fn fetch_http_url(http_rpc: Option<&str>, ws_url: &str) -> Option<String> {
     }
 }
 
-/// The fork always replaces these pallets' state with the dev chain's so that
-/// Alice is the sole validator.
+/// Unless `--keep-validators` is given, the fork replaces these pallets' state
+/// with its own validator genesis (by default the dev chain's, so that Alice is
+/// the sole validator).
 const VALIDATOR_PALLETS: [&str; 6] = [
     "Babe",
     "Grandpa",
@@ -1160,11 +1330,48 @@ const VALIDATOR_PALLETS: [&str; 6] = [
 ];
 
 #[tokio::main]
-#[allow(clippy::too_many_lines)]
 async fn main() -> Result<()> {
     color_eyre::install()?;
 
-    let cli = cli::Cli::parse();
+    let cli = cli::Cli::load()?;
+    match &cli.command {
+        Some(cli::Command::Verify(args)) => return verify::run(args),
+        Some(cli::Command::Run(args)) => return node::run(args).await,
+        Some(cli::Command::UpgradeTest(args)) => return upgrade::upgrade_test(args).await,
+        Some(cli::Command::TryUpgrade(args)) => return try_upgrade::run(args).await,
+        None => {}
+    }
+    build_fork(cli).await
+}
+
+/// Build the fork `cli` describes, writing its chain-spec to `--out`.
+#[allow(clippy::too_many_lines)]
+async fn build_fork(cli: cli::Cli) -> Result<()> {
+    let binary = cli
+        .binary
+        .clone()
+        .ok_or_else(|| eyre!("missing `--bin` (or `bin` in the --config recipe)"))?;
+    let original_chain = cli
+        .original_chain
+        .clone()
+        .ok_or_else(|| eyre!("missing `--orig` (or `orig` in the --config recipe)"))?;
+    let raw_override_args = overrides::RawOverrideArgs::from_cli(&cli)?;
+    let user_funding = accounts::funding_from_cli(&cli)?;
+    let sudo_account = match &cli.sudo {
+        _ if cli.no_sudo => None,
+        Some(account) => {
+            Some(accounts::parse_account(account).map_err(|e| eyre!("invalid --sudo: {e}"))?)
+        }
+        None => Some(account_id_from_seed_hex(ALICE_SEED_HEX)?),
+    };
+    let impersonations = impersonate::impersonations_from_cli(&cli)?;
+    let fork_validators = validators::validators_from_cli(&cli)?;
+    let spec_metadata = chain_spec::SpecMetadata::from_cli(&cli)?;
+    if cli.keep_validators && !fork_validators.is_empty() {
+        return Err(eyre!("--keep-validators conflicts with --validators"));
+    }
+    let validator_stake = accounts::parse_amount(&cli.validator_stake)
+        .map_err(|e| eyre!("invalid --validator-stake: {e}"))?;
 
     let rpc_url = parse_rpc_uri(&cli.rpc)?;
 
@@ -1179,15 +1386,48 @@ async fn main() -> Result<()> {
             Some(PathBuf::from(path))
         }
     };
+    if cli.preserve_stakers && storage_path.is_none() {
+        return Err(eyre!(
+            "--preserve-stakers needs the original chain's state; it can't be used with --storage none"
+        ));
+    }
 
+    let at_ref = cli.at.clone().unwrap_or(BlockRef::Finalized);
+    let mut state_at = None;
     if let Some(path) = &storage_path {
         if path.exists() {
             println!("using existing storage at {}", path.display());
+            match StateRecord::read(path)? {
+                Some(record) => {
+                    if let Some(at) = cli.at.as_ref().filter(|at| !record.matches(at)) {
+                        return Err(eyre!(
+                            "{} was fetched at block {} ({}), not --at {at}; delete it (or pass \
+                             another --storage path) to refetch",
+                            path.display(),
+                            record.at,
+                            record.hash
+                        ));
+                    }
+                    println!("state fetched at block {} ({})", record.at, record.hash);
+                    state_at = Some(record.hash);
+                }
+                None => println!(
+                    "{}",
+                    style(format!(
+                        "Warning: no record of the block {} was fetched at; its metadata is \
+                         read at --at {at_ref}",
+                        path.display()
+                    ))
+                    .yellow()
+                ),
+            }
         } else {
             let http_url = fetch_http_url(cli.http_rpc.as_deref(), &cli.rpc);
             let pool =
                 NodePool::connect(&rpc_url, http_url.as_deref(), cli.rpc_connections).await?;
-            let at = resolve_block_hash(pool.get(0), cli.at).await?;
+            let at = resolve_block(pool.get(0), &at_ref).await?;
+            println!("fetching state at block {at_ref} ({at})");
+            pool.check_state_available(&at).await?;
             fetch_storage_to_file(
                 &pool,
                 &at,
@@ -1196,22 +1436,37 @@ async fn main() -> Result<()> {
                 cli.key_scan_concurrency,
             )
             .await?;
+            StateRecord {
+                at: at_ref.to_string(),
+                hash: at.clone(),
+            }
+            .write(path)?;
             println!(
                 "cached fetched state at {} (reused on the next run; delete it to refetch)",
                 path.display()
             );
+            state_at = Some(at);
         }
     }
 
-    let orig_spec = build_spec(&cli.binary, cli.original_chain).await?;
-    let mut spec = build_spec(&cli.binary, cli.base_chain).await?;
+    let orig_spec = build_spec(&binary, original_chain).await?;
+    let mut spec = build_spec(&binary, cli.base_chain).await?;
 
     spec.name = cli
         .name
         .unwrap_or_else(|| orig_spec.name.joined_with("-fork"));
     spec.id = cli.id.unwrap_or_else(|| orig_spec.id.joined_with("-fork"));
-    spec.protocol_id.clone_from(&orig_spec.protocol_id);
+    spec.protocol_id = Some(cli.protocol_id.unwrap_or_else(|| {
+        chain_spec::unique_protocol_id(
+            orig_spec.protocol_id.as_deref().unwrap_or(&orig_spec.id),
+            &spec.name,
+            &spec.id,
+        )
+    }));
     spec.boot_nodes = vec![];
+    spec.telemetry_endpoints = None;
+    spec_metadata.apply(&mut spec);
+    chain_spec::check_isolated(&spec, &orig_spec, cli.allow_original_network)?;
 
     let mut excludes: HashSet<&str> = if cli.no_default_excludes {
         HashSet::default()
@@ -1230,19 +1485,59 @@ async fn main() -> Result<()> {
         .collect()
     };
 
+    if cli.keep_validators {
+        for pallet in VALIDATOR_PALLETS {
+            excludes.remove(pallet);
+        }
+    }
+
     if let Some(extra_excludes) = &cli.exclude_pallets {
         excludes.extend(extra_excludes.iter().map(String::as_str));
     }
 
+    // A base spec with `Sudo.Key` means the runtime has the Sudo pallet, whose
+    // key can be set without the metadata.
+    let base_has_sudo = spec
+        .genesis
+        .raw
+        .top
+        .contains_key(&storage_prefix("Sudo", "Key"));
+    let needs_metadata = cli.pallets.is_none()
+        || cli.include_items.is_some()
+        || cli.exclude_items.is_some()
+        || raw_override_args.needs_metadata()
+        || !fork_validators.is_empty()
+        || (sudo_account.is_some() && !base_has_sudo)
+        || !impersonations.is_empty()
+        || cli.preserve_stakers
+        || cli.report_orphaned_locks
+        || cli.remove_orphaned_locks;
+    let meta = if needs_metadata {
+        let http_url = fetch_http_url(cli.http_rpc.as_deref(), &cli.rpc);
+        let pool = NodePool::connect(&rpc_url, http_url.as_deref(), 1).await?;
+        // Decode the state with the metadata of the block it was fetched at
+        // (or, for a storage file without a record, of `--at`), not the live
+        // chain's.
+        let at = match &state_at {
+            Some(at) => at.clone(),
+            None => resolve_block(pool.get(0), &at_ref).await?,
+        };
+        println!("fetching metadata at block {at}");
+        Some(pool.get(0).metadata(&at).await?)
+    } else {
+        None
+    };
+
     let mut include_prefixes = vec![
         storage_prefix("System", "Account"), // System.Account
     ];
-    if let Some(pallets) = cli.pallets {
+    if let Some(pallets) = &cli.pallets {
         include_prefixes.extend(pallets.iter().map(|n| module_prefix(n)));
-    } else {
-        let api = ApiClient::<CreditcoinConfig>::from_url(rpc_url.to_string()).await?;
-        let meta = api.rpc().metadata().await?;
-        for pallet in &meta.runtime_metadata().pallets {
+    } else if cli.include_items.is_none() {
+        let meta = meta
+            .as_ref()
+            .expect("metadata is fetched without --pallets");
+        for pallet in &meta.pallets {
             let n = &pallet.name;
             if pallet.storage.is_some() && !excludes.contains(n.as_str()) {
                 let hashed = module_prefix(n);
@@ -1250,11 +1545,46 @@ async fn main() -> Result<()> {
             }
         }
     }
+    if let Some(items) = &cli.include_items {
+        let meta = meta
+            .as_ref()
+            .expect("metadata is fetched for --include-items");
+        // Listed items win over pallet excludes, but not over the validator
+        // pallets the fork replaces.
+        let replaced: &[&str] = if cli.keep_validators {
+            &[]
+        } else {
+            &VALIDATOR_PALLETS
+        };
+        metadata::check_items_not_replaced(items, replaced)?;
+        include_prefixes.extend(
+            metadata::resolve_item_patterns(meta, items)?
+                .iter()
+                .map(metadata::StorageItem::prefix),
+        );
+    }
 
-    // The fork always injects the dev chain's validator genesis, so drop the
+    // Unless `--keep-validators` is given, the fork injects its own validator
+    // genesis (the dev chain's, or the `--validators` set), so drop the
     // original chain's validator state (from storage and the base spec alike).
-    let mut exclude_prefixes: Vec<String> =
+    let validator_prefixes: Vec<String> =
         VALIDATOR_PALLETS.iter().map(|p| module_prefix(p)).collect();
+    let replaced_prefixes = if cli.keep_validators {
+        Vec::new()
+    } else {
+        validator_prefixes.clone()
+    };
+    let mut exclude_prefixes = Vec::new();
+    if let Some(items) = &cli.exclude_items {
+        let meta = meta
+            .as_ref()
+            .expect("metadata is fetched for --exclude-items");
+        exclude_prefixes.extend(
+            metadata::resolve_item_patterns(meta, items)?
+                .iter()
+                .map(metadata::StorageItem::prefix),
+        );
+    }
     if cli.usc {
         exclude_prefixes.push(storage_prefix("Attestation", "ActiveAttestors"));
         exclude_prefixes.push(storage_prefix("Attestation", "TargetSampleSize"));
@@ -1263,23 +1593,134 @@ async fn main() -> Result<()> {
         exclude_prefixes.push(attestors_storage_key_prefix(cli.usc_chain_key));
     }
 
+    let raw_overrides = raw_override_args.resolve(meta.as_ref())?;
+    exclude_prefixes.extend(raw_overrides.remove_prefix.iter().cloned());
+
     // make sure to remove System.LastRuntimeUpgrade to trigger a migration
-    let remove_exact = HashSet::from([storage_prefix("System", "LastRuntimeUpgrade")]);
+    let mut remove_exact = HashSet::from([storage_prefix("System", "LastRuntimeUpgrade")]);
+    remove_exact.extend(raw_overrides.remove.iter().cloned());
+
+    // With `--keep-validators`, the base spec's validator state must not leak
+    // into the original chain's: drop base-spec keys the original doesn't have.
+    if cli.keep_validators {
+        if let Some(path) = &storage_path {
+            let mut original_keys = HashSet::new();
+            for prefix in &validator_prefixes {
+                original_keys.extend(read_keys_with_prefix(path, prefix)?);
+            }
+            remove_exact.extend(
+                spec.genesis
+                    .raw
+                    .top
+                    .keys()
+                    .filter(|k| validator_prefixes.iter().any(|p| k.starts_with(p.as_str())))
+                    .filter(|k| !original_keys.contains(*k))
+                    .cloned(),
+            );
+        }
+    }
 
-    let filter = TopFilter {
+    let mut filter = TopFilter {
         include_prefixes,
+        replaced_prefixes,
         exclude_prefixes,
         remove_exact,
     };
 
+    // Move each `--impersonate` account's state onto its target: re-key the
+    // entries keyed by it, rewrite it inside values, and drop the originals.
+    let transplant = match &meta {
+        Some(meta) if !impersonations.is_empty() => {
+            Some(impersonate::Transplant::new(meta, &impersonations)?)
+        }
+        _ => None,
+    };
+    let mut transplanted = Vec::new();
+    if let (Some(path), Some(transplant)) = (&storage_path, &transplant) {
+        // Only the entries the transplant changes are held in memory.
+        let moved = read_mapped_entries(path, |key, value| {
+            if !filter.keeps_storage_key(key) || !transplant.covers(key) {
+                return None;
+            }
+            transplant
+                .rewrite(key, value)
+                .map(|entry| (key.to_owned(), entry))
+        })?;
+        let mut moved_keys = HashSet::new();
+        for (key, (new_key, new_value)) in moved {
+            if new_key != key {
+                filter.remove_exact.insert(key);
+                moved_keys.insert(new_key.clone());
+            }
+            transplanted.push((new_key, new_value));
+        }
+        // The targets' own state, which the moved entries would otherwise
+        // overwrite or be mixed in with: storage shadows the base spec.
+        let mut existing: HashMap<String, String> = read_matching_entries(path, |key, _| {
+            moved_keys.contains(key) && filter.keeps_storage_key(key)
+        })?
+        .into_iter()
+        .collect();
+        for (key, value) in &spec.genesis.raw.top {
+            if moved_keys.contains(key) && filter.keeps_base_key(key) {
+                if let Some(value) = value.as_str() {
+                    existing
+                        .entry(key.clone())
+                        .or_insert_with(|| value.to_owned());
+                }
+            }
+        }
+        let merged = impersonate::merge_target_state(&mut transplanted, &existing)?;
+        println!(
+            "Impersonation moved or rewrote {} storage entries",
+            transplanted.len()
+        );
+        if merged > 0 {
+            println!(
+                "{}",
+                style(format!(
+                    "Warning: {merged} impersonation target(s) already had an account on the fork; their balances were merged with the moved ones"
+                ))
+                .yellow()
+            );
+        }
+    }
+
     // The few values main() needs to read are pulled from the storage file in a
     // single streaming pass instead of holding the whole state in memory.
     let code_key = b":code".to_hex();
     let issuance_key = storage_prefix("Balances", "TotalIssuance");
     let alice = account_id_from_seed_hex(ALICE_SEED_HEX)?;
     let bob = account_id_from_seed_hex(BOB_SEED_HEX)?;
-    let alice_acct_key = system_account_storage_key(&alice);
-    let bob_acct_key = system_account_storage_key(&bob);
+
+    // Accounts whose free balance is set on the fork: Alice and Bob for
+    // `--usc`, then `--fund-file`/`--fund` (the last entry for an account wins),
+    // then any `--validators` not funded explicitly, with their stake plus 10 CTC.
+    let mut funding = Vec::new();
+    if cli.usc {
+        for account in [alice, bob] {
+            funding.push(accounts::Funding {
+                account,
+                amount: TEN_CTC_PLANCK,
+            });
+        }
+    }
+    funding.extend(user_funding);
+    for v in &fork_validators {
+        match funding.iter().rev().find(|f| f.account == v.account) {
+            Some(f) if f.amount < validator_stake => {
+                return Err(eyre!(
+                    "validator `{}` is funded with less than --validator-stake",
+                    v.uri
+                ))
+            }
+            Some(_) => {}
+            None => funding.push(accounts::Funding {
+                account: v.account,
+                amount: validator_stake.saturating_add(TEN_CTC_PLANCK),
+            }),
+        }
+    }
 
     // The Staking pallet is dropped from the fork (the fork runs with the dev
     // chain's validators), but NominationPools state is carried over. A pool
@@ -1292,14 +1733,17 @@ async fn main() -> Result<()> {
     // (`--pallets`/`--exclude-pallets` without NominationPools) or their
     // balance locks (without Balances): orphan staking entries, or a ledger
     // whose funds the balances pallet does not lock, are worse than dead pools.
+    // With `--keep-validators` the whole Staking pallet is carried over anyway.
     let bonded_pools_prefix = storage_prefix("NominationPools", "BondedPools");
     let preserve_pools = filter.keeps_storage_key(&bonded_pools_prefix)
         && filter.keeps_storage_key(&storage_prefix("Balances", "Locks"));
     let pool_ids: Vec<u32> = match &storage_path {
-        Some(path) if preserve_pools => read_keys_with_prefix(path, &bonded_pools_prefix)?
-            .iter()
-            .filter_map(|key| pool_id_from_bonded_pools_key(key, &bonded_pools_prefix))
-            .collect(),
+        Some(path) if preserve_pools && !cli.keep_validators && !cli.preserve_stakers => {
+            read_keys_with_prefix(path, &bonded_pools_prefix)?
+                .iter()
+                .filter_map(|key| pool_id_from_bonded_pools_key(key, &bonded_pools_prefix))
+                .collect()
+        }
         _ => Vec::new(),
     };
     let pool_staking_keys: Vec<(u32, PoolStakingKeys)> = pool_ids
@@ -1318,12 +1762,22 @@ async fn main() -> Result<()> {
         .collect();
 
     let mut wanted = HashSet::from([code_key.clone()]);
-    if cli.usc {
-        wanted.extend([
-            issuance_key.clone(),
-            alice_acct_key.clone(),
-            bob_acct_key.clone(),
-        ]);
+    if !funding.is_empty() {
+        wanted.insert(issuance_key.clone());
+        wanted.extend(
+            funding
+                .iter()
+                .map(|f| system_account_storage_key(&f.account)),
+        );
+    }
+    let epoch_config_key = storage_prefix("Babe", "EpochConfig");
+    if !fork_validators.is_empty() {
+        wanted.insert(epoch_config_key.clone());
+        wanted.extend(validators::setting_keys(&VALIDATOR_PALLETS));
+    }
+    let current_era_key = storage_prefix("Staking", "CurrentEra");
+    if !pool_staking_keys.is_empty() || cli.preserve_stakers || !transplanted.is_empty() {
+        wanted.insert(current_era_key.clone());
     }
     for (_, keys) in &pool_staking_keys {
         wanted.extend([keys.bonded.clone(), keys.ledger.clone(), keys.payee.clone()]);
@@ -1333,18 +1787,141 @@ async fn main() -> Result<()> {
         None => HashMap::default(),
     };
 
-    let wasm_hex = if let Some(runtime_path) = &cli.runtime {
+    let (wasm_hex, runtime_source) = if let Some(runtime_path) = &cli.runtime {
         println!("Reading from runtime wasm file: {}", runtime_path.display());
-        read_wasm_hex(runtime_path).await?
+        (
+            read_wasm_hex(runtime_path, cli.compress_runtime, cli.max_code_size).await?,
+            RuntimeSource::File {
+                path: runtime_path.clone(),
+            },
+        )
+    } else if let Some(block) = &cli.runtime_at {
+        let http_url = fetch_http_url(cli.http_rpc.as_deref(), &cli.rpc);
+        let pool = NodePool::connect(&rpc_url, http_url.as_deref(), 1).await?;
+        let hash = resolve_block(pool.get(0), block).await?;
+        pool.check_state_available(&hash).await?;
+        println!("Fetching the runtime at block {block} ({hash})");
+        let code = pool
+            .get(0)
+            .storage_value(&code_key, &hash)
+            .await?
+            .ok_or_else(|| eyre!("no runtime code at block {block}"))?;
+        (
+            code,
+            RuntimeSource::Block {
+                block: block.to_string(),
+                hash,
+            },
+        )
     } else {
-        selected
+        (
+            selected
+                .get(&code_key)
+                .expect("storage should include the runtime code")
+                .clone(),
+            RuntimeSource::State,
+        )
+    };
+
+    // Compare the fork's runtime with the original chain's and with `--bin`'s
+    // own (the base spec's genesis code).
+    let version_of = |code: &str| {
+        hex::decode(code.trim_start_matches("0x"))
+            .err_into()
+            .and_then(|blob| runtime::RuntimeVersion::of_blob(&blob))
+    };
+    // A blob without a `runtime_version` section can't be checked: warn and
+    // skip the checks that need its version.
+    let checked_version_of = |code: &str, what: &str| match version_of(code) {
+        Ok(version) => {
+            println!("{what}: {version}");
+            Some(version)
+        }
+        Err(e) => {
+            println!(
+                "{}",
+                style(format!(
+                    "Warning: {what} has no readable version ({e}); skipping its compatibility checks"
+                ))
+                .yellow()
+            );
+            None
+        }
+    };
+    let fork_version = checked_version_of(&wasm_hex, "Fork runtime");
+    let original_version = match selected.get(&code_key) {
+        Some(code) if !matches!(runtime_source, RuntimeSource::State) => {
+            checked_version_of(code, "On-chain runtime")
+        }
+        _ => None,
+    };
+    if let Some(fork_version) = &fork_version {
+        let node_version = spec
+            .genesis
+            .raw
+            .top
             .get(&code_key)
-            .expect("storage should include the runtime code")
-            .clone()
+            .and_then(JsonValue::as_str)
+            .and_then(|code| version_of(code).ok());
+        let (errors, warnings) = runtime::compatibility(
+            original_version.as_ref().unwrap_or(fork_version),
+            fork_version,
+            node_version.as_ref(),
+        );
+        if !errors.is_empty() && !cli.allow_runtime_mismatch {
+            return Err(eyre!(
+                "{} (pass --allow-runtime-mismatch to fork anyway)",
+                errors.join("; ")
+            ));
+        }
+        for warning in errors.iter().chain(&warnings) {
+            println!("{}", style(format!("Warning: {warning}")).yellow());
+        }
+    }
+
+    // `codeSubstitutes`: the base spec's (unless cleared) and `--code-substitute`.
+    let mut code_substitutes = match &spec.code_substitutes {
+        JsonValue::Object(map) if !cli.clear_code_substitutes => map.clone(),
+        _ => serde_json::Map::new(),
     };
+    for entry in cli.code_substitute.iter().flatten() {
+        let (block, path) = runtime::parse_code_substitute(entry)
+            .map_err(|e| eyre!("invalid --code-substitute: {e}"))?;
+        let code = read_wasm_hex(&path, cli.compress_runtime, cli.max_code_size).await?;
+        let version = checked_version_of(&code, &format!("Code substitute from block #{block}"));
+        if let (Some(version), Some(fork_version)) = (&version, &fork_version) {
+            if version.spec_name != fork_version.spec_name && !cli.allow_runtime_mismatch {
+                return Err(eyre!(
+                    "{}: the runtime is `{}`, not `{}` (pass --allow-runtime-mismatch to fork anyway)",
+                    path.display(),
+                    version.spec_name,
+                    fork_version.spec_name
+                ));
+            }
+            // Nodes only use a substitute while the chain's spec version is its own.
+            if version.spec_version != fork_version.spec_version {
+                println!(
+                    "{}",
+                    style(format!(
+                        "Warning: {} has spec version {}, the fork's runtime {}; nodes ignore it while they differ",
+                        path.display(),
+                        version.spec_version,
+                        fork_version.spec_version
+                    ))
+                    .yellow()
+                );
+            }
+        }
+        code_substitutes.insert(block.to_string(), code.into());
+    }
+    spec.code_substitutes = JsonValue::Object(code_substitutes);
 
     // Entries that win over both storage and the base spec.
     let mut overrides = serde_json::Map::new();
+    let transplanted_keys: HashSet<String> = transplanted.iter().map(|(k, _)| k.clone()).collect();
+    for (key, value) in transplanted {
+        overrides.insert(key, value.into());
+    }
 
     // Overwrite the on-chain wasm blob
     overrides.insert(code_key, wasm_hex.into());
@@ -1352,26 +1929,37 @@ async fn main() -> Result<()> {
     // Make sure that the genesis state is different
     overrides.insert("0xdeadbeef".to_owned(), "0x1".into());
 
-    // Always set the sudo key to Alice, replacing the original chain's key.
+    // Hand governance to `--sudo` (Alice by default), replacing the original
+    // chain's sudo key, or its collectives' members (dropping their pending
+    // motions) on sudo-less runtimes.
     // creditcoin3's runtime-upgrade CI forks testnet/mainnet without `--usc`
     // and submits sudo calls signed by //Alice against the fork.
-    overrides.insert(storage_prefix("Sudo", "Key"), alice.to_hex().into());
+    let governed = match &sudo_account {
+        Some(account) if base_has_sudo => governance::set_sudo_key(account, &mut overrides).pallets,
+        Some(account) => {
+            let meta = meta.as_ref().expect("metadata is fetched for the sudo key");
+            let control = governance::grant_control(meta, account, &mut overrides)?;
+            filter.exclude_prefixes.extend(control.stale_prefixes);
+            control.pallets
+        }
+        None => Vec::new(),
+    };
+
+    // Reads a merged-state value the way the old in-memory merge saw it:
+    // filtered storage first, then the base spec.
+    let merged_value = |key: &str| -> Option<JsonValue> {
+        if filter.keeps_storage_key(key) {
+            if let Some(v) = selected.get(key) {
+                return Some(JsonValue::String(v.clone()));
+            }
+        }
+        spec.genesis.raw.top.get(key).cloned()
+    };
 
     if cli.usc {
         // USC component: Alice and Bob from hex seeds; set Attestation pallet genesis
         let ck = cli.usc_chain_key;
 
-        // Reads a merged-state value the way the old in-memory merge saw it:
-        // filtered storage first, then the base spec.
-        let merged_value = |key: &str| -> Option<JsonValue> {
-            if filter.keeps_storage_key(key) {
-                if let Some(v) = selected.get(key) {
-                    return Some(JsonValue::String(v.clone()));
-                }
-            }
-            spec.genesis.raw.top.get(key).cloned()
-        };
-
         let alice_bls = bls_public_key_from_hex_seed_uri(ALICE_SEED_HEX)?;
         let bob_bls = bls_public_key_from_hex_seed_uri(BOB_SEED_HEX)?;
 
@@ -1388,58 +1976,132 @@ async fn main() -> Result<()> {
             active_attestors_value(&alice, &bob).into(),
         );
         overrides.insert(target_sample_size_storage_key(ck), "0x02000000".into());
+    }
 
-        // 10 CTC each on `System.Account`; keep `Balances::TotalIssuance` consistent with prior state.
-        let old_issuance = merged_value(&issuance_key)
-            .as_ref()
+    // Fund accounts on `System.Account`, keeping `Balances::TotalIssuance`
+    // consistent with their prior balances.
+    if !funding.is_empty() {
+        accounts::apply_funding(&funding, &merged_value, &issuance_key, &mut overrides);
+    }
+
+    if cli.keep_validators {
+        println!(
+            "{}",
+            style(
+                "warning: keeping the original chain's validator set. The fork only produces \
+                 and finalizes blocks once nodes holding the original validators' session keys \
+                 (babe, gran, imon, audi) run with --validator: insert those keys into each \
+                 node's keystore, and run enough of them for BABE slots to be claimed and for \
+                 more than 2/3 of the GRANDPA authority set to vote."
+            )
+            .yellow()
+        );
+    } else if fork_validators.is_empty() {
+        // Inject the dev chain's validator genesis so Alice is the sole authority.
+        let dev_spec = build_spec(&binary, Chain::Dev).await?;
+        for (k, v) in &dev_spec.genesis.raw.top {
+            if validator_prefixes.iter().any(|p| k.starts_with(p.as_str())) {
+                overrides.insert(k.clone(), v.clone());
+            }
+        }
+    } else {
+        // Write genesis for the `--validators` set, keeping the original
+        // chain's BABE epoch configuration (or the base spec's).
+        let meta = meta.as_ref().expect("metadata is fetched for --validators");
+        let total_issuance = overrides
+            .get(&issuance_key)
             .and_then(json_hex_bytes)
             .and_then(|b| u128_le_from_first_16(&b))
             .unwrap_or(0);
-
-        let old_alice_free = merged_value(&alice_acct_key)
-            .as_ref()
-            .and_then(free_balance_from_account_json)
-            .unwrap_or(0);
-        let old_bob_free = merged_value(&bob_acct_key)
-            .as_ref()
-            .and_then(free_balance_from_account_json)
-            .unwrap_or(0);
-
-        let new_issuance = old_issuance
-            .saturating_sub(old_alice_free)
-            .saturating_sub(old_bob_free)
-            .saturating_add(TEN_CTC_PLANCK)
-            .saturating_add(TEN_CTC_PLANCK);
-
-        overrides.insert(
-            alice_acct_key,
-            system_account_info_with_free_balance(TEN_CTC_PLANCK).into(),
+        let babe_epoch_config = selected.get(&epoch_config_key).cloned().or_else(|| {
+            spec.genesis
+                .raw
+                .top
+                .get(&epoch_config_key)
+                .and_then(|v| v.as_str().map(str::to_owned))
+        });
+        validators::carry_settings(
+            &VALIDATOR_PALLETS,
+            &selected,
+            &spec.genesis.raw.top,
+            &mut overrides,
         );
-        overrides.insert(
-            bob_acct_key,
-            system_account_info_with_free_balance(TEN_CTC_PLANCK).into(),
-        );
-        overrides.insert(issuance_key, scale_u128_storage_hex(new_issuance).into());
-    }
-
-    // Inject the dev chain's validator genesis so Alice is the sole authority.
-    let dev_spec = build_spec(&cli.binary, Chain::Dev).await?;
-    let validator_prefixes: Vec<_> = VALIDATOR_PALLETS.iter().map(|p| module_prefix(p)).collect();
-    for (k, v) in &dev_spec.genesis.raw.top {
-        if validator_prefixes.iter().any(|p| k.starts_with(p.as_str())) {
-            overrides.insert(k.clone(), v.clone());
-        }
+        validators::inject_validator_genesis(
+            meta,
+            &validators::ValidatorGenesis {
+                validators: &fork_validators,
+                stake: validator_stake,
+                babe_epoch_config,
+                total_issuance,
+            },
+            &mut overrides,
+        )?;
     }
 
     // Re-add the nomination pools' staking entries after the dev-validator
     // injection so they are not dropped with the rest of the Staking pallet.
-    // Known imperfection: a preserved ledger's `unlocking` chunks reference the
-    // original chain's era numbers, which the fork restarts near zero, so
-    // in-flight unbonds stay unwithdrawable and occupy MaxUnlockingChunks
-    // slots. Fresh join/bond-extra/unbond all work.
+    // The fork restarts eras near zero, so the ledgers' `unlocking` chunks
+    // (and the pools' and members' unbonding eras) are shifted onto the fork's
+    // era numbering: in-flight unbonds become withdrawable as many eras from
+    // now as they would have on the original chain.
+    let era_rebase = unbonding::EraRebase {
+        original_era: selected
+            .get(&current_era_key)
+            .and_then(|v| hex::decode(v.trim_start_matches("0x")).ok())
+            .and_then(|b| Some(u32::from_le_bytes(b.get(..4)?.try_into().ok()?)))
+            .unwrap_or(0),
+        fork_era: overrides
+            .get(&current_era_key)
+            .and_then(json_hex_bytes)
+            .and_then(|b| Some(u32::from_le_bytes(b.get(..4)?.try_into().ok()?)))
+            .unwrap_or(0),
+    };
+    // With `--keep-validators` the original eras carry on unchanged.
+    let era_rebase = if cli.keep_validators {
+        unbonding::EraRebase {
+            fork_era: era_rebase.original_era,
+            ..era_rebase
+        }
+    } else {
+        era_rebase
+    };
+    let rebase_meta = meta
+        .as_ref()
+        .filter(|_| !pool_staking_keys.is_empty() || cli.preserve_stakers);
+    if cli.preserve_stakers {
+        let meta = rebase_meta.expect("metadata is fetched for --preserve-stakers");
+        let path = storage_path
+            .as_ref()
+            .expect("--preserve-stakers is rejected without storage");
+        let total_issuance = overrides
+            .get(&issuance_key)
+            .and_then(json_hex_bytes)
+            .or_else(|| {
+                merged_value(&issuance_key)
+                    .as_ref()
+                    .and_then(json_hex_bytes)
+            })
+            .and_then(|b| u128_le_from_first_16(&b))
+            .unwrap_or(0);
+        stakers::preserve_stakers(
+            meta,
+            path,
+            |key| filter.keeps_replaced_key(key),
+            transplant.as_ref(),
+            era_rebase,
+            total_issuance,
+            &mut overrides,
+        )?;
+    }
+    if !pool_staking_keys.is_empty() && rebase_meta.is_none() {
+        println!(
+            "{}",
+            style("warning: no runtime metadata; preserved pool ledgers keep the original chain's unbonding eras").yellow()
+        );
+    }
     let mut preserved_count = 0usize;
     for (pool_id, keys) in &pool_staking_keys {
-        if !selected.contains_key(&keys.ledger) {
+        let Some(ledger) = selected.get(&keys.ledger) else {
             println!(
                 "{}",
                 style(format!(
@@ -1448,17 +2110,104 @@ async fn main() -> Result<()> {
                 .yellow()
             );
             continue;
-        }
-        for key in [&keys.bonded, &keys.ledger, &keys.payee] {
+        };
+        for key in [&keys.bonded, &keys.payee] {
             if let Some(value) = selected.get(key) {
                 overrides.insert(key.clone(), value.clone().into());
             }
         }
+        let ledger = match rebase_meta {
+            Some(meta) => unbonding::rebase_value(meta, "Staking", "Ledger", ledger, |l| {
+                era_rebase.ledger(l)
+            })?
+            .unwrap_or_else(|| ledger.clone()),
+            None => ledger.clone(),
+        };
+        overrides.insert(keys.ledger.clone(), ledger.into());
         preserved_count += 1;
     }
     if preserved_count > 0 {
         println!("Preserved staking ledgers for {preserved_count} nomination pool(s)");
     }
+    if let (Some(meta), Some(path)) = (rebase_meta, &storage_path) {
+        let sub_pools_prefix = storage_prefix("NominationPools", "SubPoolsStorage");
+        let members_prefix = storage_prefix("NominationPools", "PoolMembers");
+        let entries = read_matching_entries(path, |k, _| {
+            (k.starts_with(&sub_pools_prefix) || k.starts_with(&members_prefix))
+                && filter.keeps_storage_key(k)
+                && !transplanted_keys.contains(k)
+        })?;
+        let mut rebased = 0usize;
+        for (key, value) in entries {
+            let new_value = if key.starts_with(&sub_pools_prefix) {
+                unbonding::rebase_value(meta, "NominationPools", "SubPoolsStorage", &value, |v| {
+                    era_rebase.era_map(v, "with_era")
+                })?
+            } else {
+                unbonding::rebase_value(meta, "NominationPools", "PoolMembers", &value, |v| {
+                    era_rebase.era_map(v, "unbonding_eras")
+                })?
+            };
+            if let Some(new_value) = new_value.filter(|v| *v != value) {
+                overrides.insert(key, new_value.into());
+                rebased += 1;
+            }
+        }
+        println!(
+            "Rebased unbonding eras ({} -> {}) in {rebased} pool/member entries",
+            era_rebase.original_era, era_rebase.fork_era
+        );
+    }
+    // Members and ledgers `--impersonate` moved are overrides, not storage.
+    if let Some(meta) = &meta {
+        let members_prefix = storage_prefix("NominationPools", "PoolMembers");
+        let ledger_prefix = storage_prefix("Staking", "Ledger");
+        for key in &transplanted_keys {
+            let Some(value) = overrides.get(key).and_then(JsonValue::as_str) else {
+                continue;
+            };
+            let new_value = if key.starts_with(&members_prefix) {
+                unbonding::rebase_value(meta, "NominationPools", "PoolMembers", value, |v| {
+                    era_rebase.era_map(v, "unbonding_eras")
+                })?
+            } else if key.starts_with(&ledger_prefix) {
+                unbonding::rebase_value(meta, "Staking", "Ledger", value, |l| era_rebase.ledger(l))?
+            } else {
+                continue;
+            };
+            if let Some(new_value) = new_value {
+                overrides.insert(key.clone(), new_value.into());
+            }
+        }
+    }
+
+    if cli.report_orphaned_locks || cli.remove_orphaned_locks {
+        let meta = meta
+            .as_ref()
+            .expect("metadata is fetched for orphaned locks");
+        locks::check_orphaned_locks(
+            meta,
+            storage_path.as_deref(),
+            &spec.genesis.raw.top,
+            &mut filter,
+            &mut overrides,
+            cli.remove_orphaned_locks,
+        )?;
+    }
+
+    // Explicit `--set`/`--remove`/`--remove-prefix` surgery wins over
+    // everything above.
+    raw_overrides.apply(&mut overrides);
+
+    if cli.reconcile_issuance {
+        accounts::reconcile_issuance(
+            storage_path.as_deref(),
+            &spec.genesis.raw.top,
+            &filter,
+            &issuance_key,
+            &mut overrides,
+        )?;
+    }
 
     println!("{}", style("Writing chain specification for fork").green());
 
@@ -1490,8 +2239,32 @@ async fn main() -> Result<()> {
     serde_json::to_writer_pretty(&mut writer, &out)?;
     writer.flush()?;
 
+    Provenance {
+        original: orig_spec.id.clone(),
+        rpc: cli.rpc.clone(),
+        state_at,
+        runtime: runtime_source,
+        runtime_version: fork_version.map_or_else(
+            || "unknown".to_owned(),
+            |version| format!("{} {}", version.spec_name, version.spec_version),
+        ),
+    }
+    .write(&cli.out)?;
+
     println!("{}", style("Done!").green());
 
+    if cli.verify {
+        verify::verify_spec(
+            &cli.out,
+            &verify::Expectations {
+                original_top: Some(&orig_spec.genesis.raw.top),
+                excluded: [&filter.replaced_prefixes[..], &filter.exclude_prefixes].concat(),
+                allowed: Some(&overrides),
+                governance: governed,
+            },
+        )?;
+    }
+
     Ok(())
 }
 
@@ -0,0 +1,281 @@
+use color_eyre::{eyre::eyre, Result};
+use frame_metadata::{
+    PalletStorageMetadata, RuntimeMetadataV14, StorageEntryMetadata, StorageEntryType,
+    StorageHasher,
+};
+use scale_info::{form::PortableForm, PortableRegistry, TypeDef};
+use serde_json::Value as JsonValue;
+use sp_core::hashing::{blake2_128, blake2_256, twox_128, twox_256, twox_64};
+
+use crate::scale::{self, encode_json};
+use crate::{storage_prefix, ToHex};
+
+/// A storage item resolved against the runtime metadata.
+pub struct StorageItem<'a> {
+    /// The pallet's storage prefix (usually, but not necessarily, its name).
+    pub pallet_prefix: &'a str,
+    pub entry: &'a StorageEntryMetadata<PortableForm>,
+}
+
+impl StorageItem<'_> {
+    /// `twox_128(pallet) ++ twox_128(item)`, shared by every key of the item.
+    pub fn prefix(&self) -> String {
+        storage_prefix(self.pallet_prefix, &self.entry.name)
+    }
+
+    /// The registry type of the item's values.
+    pub fn value_type(&self) -> u32 {
+        match &self.entry.ty {
+            StorageEntryType::Plain(ty) => ty.id(),
+            StorageEntryType::Map { value, .. } => value.id(),
+        }
+    }
+
+    /// The hasher and registry type of each key component: none for a plain
+    /// item, one per hasher for a map.
+    pub fn key_components(
+        &self,
+        registry: &PortableRegistry,
+    ) -> Result<Vec<(&StorageHasher, u32)>> {
+        match &self.entry.ty {
+            StorageEntryType::Plain(_) => Ok(Vec::new()),
+            StorageEntryType::Map { hashers, key, .. } if hashers.len() == 1 => {
+                Ok(vec![(&hashers[0], key.id())])
+            }
+            StorageEntryType::Map { hashers, key, .. } => {
+                match scale::resolve(registry, key.id())?.type_def() {
+                    TypeDef::Tuple(tuple) if tuple.fields().len() == hashers.len() => Ok(hashers
+                        .iter()
+                        .zip(tuple.fields())
+                        .map(|(hasher, ty)| (hasher, ty.id()))
+                        .collect()),
+                    _ => Err(eyre!("unexpected key type for `{}`", self.entry.name)),
+                }
+            }
+        }
+    }
+
+    /// The full storage key of the entry addressed by `args`: none for a
+    /// plain item, one JSON value per hasher for a map.
+    pub fn key(&self, registry: &PortableRegistry, args: &[JsonValue]) -> Result<String> {
+        let mut key = hex::decode(&self.prefix()[2..]).expect("prefix is valid hex");
+        let components = self.key_components(registry)?;
+        if args.len() != components.len() {
+            return Err(eyre!(
+                "`{}` takes {} key argument(s), got {}",
+                self.entry.name,
+                components.len(),
+                args.len()
+            ));
+        }
+        for ((hasher, ty), arg) in components.into_iter().zip(args) {
+            let mut encoded = Vec::new();
+            encode_json(registry, ty, arg, &mut encoded)
+                .map_err(|e| eyre!("key argument {arg}: {e}"))?;
+            append_hashed(&mut key, hasher, &encoded);
+        }
+        Ok(key.to_hex())
+    }
+}
+
+/// Append `data` to a storage key as hashed by `hasher`.
+pub fn append_hashed(key: &mut Vec<u8>, hasher: &StorageHasher, data: &[u8]) {
+    match hasher {
+        StorageHasher::Blake2_128 => key.extend_from_slice(&blake2_128(data)),
+        StorageHasher::Blake2_256 => key.extend_from_slice(&blake2_256(data)),
+        StorageHasher::Blake2_128Concat => {
+            key.extend_from_slice(&blake2_128(data));
+            key.extend_from_slice(data);
+        }
+        StorageHasher::Twox128 => key.extend_from_slice(&twox_128(data)),
+        StorageHasher::Twox256 => key.extend_from_slice(&twox_256(data)),
+        StorageHasher::Twox64Concat => {
+            key.extend_from_slice(&twox_64(data));
+            key.extend_from_slice(data);
+        }
+        StorageHasher::Identity => key.extend_from_slice(data),
+    }
+}
+
+/// The storage metadata of the pallet called `pallet`.
+pub fn pallet_storage<'a>(
+    meta: &'a RuntimeMetadataV14,
+    pallet: &str,
+) -> Result<&'a PalletStorageMetadata<PortableForm>> {
+    let found = meta
+        .pallets
+        .iter()
+        .find(|p| p.name == pallet)
+        .ok_or_else(|| eyre!("unknown pallet `{pallet}` (not in the runtime metadata)"))?;
+    found
+        .storage
+        .as_ref()
+        .ok_or_else(|| eyre!("pallet `{pallet}` has no storage"))
+}
+
+/// Look up the storage item `pallet.item`.
+pub fn storage_item<'a>(
+    meta: &'a RuntimeMetadataV14,
+    pallet: &str,
+    item: &str,
+) -> Result<StorageItem<'a>> {
+    let storage = pallet_storage(meta, pallet)?;
+    let entry = storage
+        .entries
+        .iter()
+        .find(|e| e.name == item)
+        .ok_or_else(|| {
+            eyre!(
+                "unknown storage item `{pallet}.{item}`; `{pallet}` has: {}",
+                item_names(storage)
+            )
+        })?;
+    Ok(StorageItem {
+        pallet_prefix: &storage.prefix,
+        entry,
+    })
+}
+
+fn item_names(storage: &PalletStorageMetadata<PortableForm>) -> String {
+    storage
+        .entries
+        .iter()
+        .map(|e| e.name.as_str())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Look up `pallet.item`, or `None` if the runtime has no such item.
+pub fn find_item<'a>(
+    meta: &'a RuntimeMetadataV14,
+    pallet: &str,
+    item: &str,
+) -> Option<StorageItem<'a>> {
+    storage_item(meta, pallet, item).ok()
+}
+
+/// The SCALE value of the constant `pallet.name`.
+pub fn constant<'a>(meta: &'a RuntimeMetadataV14, pallet: &str, name: &str) -> Option<&'a [u8]> {
+    meta.pallets
+        .iter()
+        .find(|p| p.name == pallet)?
+        .constants
+        .iter()
+        .find(|c| c.name == name)
+        .map(|c| c.value.as_slice())
+}
+
+/// Encode `value` for the entry of `item` addressed by `args` and write it to
+/// `overrides`.
+pub fn put_typed(
+    meta: &RuntimeMetadataV14,
+    overrides: &mut serde_json::Map<String, JsonValue>,
+    item: &StorageItem<'_>,
+    args: &[JsonValue],
+    value: &JsonValue,
+) -> Result<()> {
+    let key = item.key(&meta.types, args)?;
+    let mut encoded = Vec::new();
+    encode_json(&meta.types, item.value_type(), value, &mut encoded)
+        .map_err(|e| eyre!("{}: {e}", item.entry.name))?;
+    overrides.insert(key, encoded.to_hex().into());
+    Ok(())
+}
+
+/// Split a `Pallet.Item` pattern into its pallet and item parts.
+pub fn parse_item_pattern(pattern: &str) -> Result<(&str, &str)> {
+    match pattern.split_once('.') {
+        Some((pallet, item)) if !pallet.is_empty() && !item.is_empty() => Ok((pallet, item)),
+        _ => Err(eyre!(
+            "invalid storage item `{pattern}`: expected `Pallet.Item` or `Pallet.Prefix*`"
+        )),
+    }
+}
+
+/// Fail if an `--include-items` pattern names an item of a pallet whose state
+/// the fork replaces: it would be dropped anyway. Items listed explicitly win
+/// over pallet excludes, so those are fine.
+pub fn check_items_not_replaced(patterns: &[String], replaced: &[&str]) -> Result<()> {
+    for pattern in patterns {
+        let (pallet, _) = parse_item_pattern(pattern)?;
+        if replaced.contains(&pallet) {
+            return Err(eyre!(
+                "--include-items `{pattern}` is in `{pallet}`, whose state the fork replaces with \
+                 its own validator genesis; pass --keep-validators to keep the original's"
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Resolve `Pallet.Item` patterns against the metadata. An item ending in `*`
+/// matches every item of the pallet starting with the text before it. Unknown
+/// pallets or items, and globs matching nothing, are errors.
+pub fn resolve_item_patterns<'a>(
+    meta: &'a RuntimeMetadataV14,
+    patterns: &[String],
+) -> Result<Vec<StorageItem<'a>>> {
+    let mut items = Vec::new();
+    for pattern in patterns {
+        let (pallet, item) = parse_item_pattern(pattern)?;
+        if let Some(item_prefix) = item.strip_suffix('*') {
+            let storage = pallet_storage(meta, pallet)?;
+            let before = items.len();
+            items.extend(
+                storage
+                    .entries
+                    .iter()
+                    .filter(|e| e.name.starts_with(item_prefix))
+                    .map(|entry| StorageItem {
+                        pallet_prefix: &storage.prefix,
+                        entry,
+                    }),
+            );
+            if items.len() == before {
+                return Err(eyre!(
+                    "`{pattern}` matches no storage items; `{pallet}` has: {}",
+                    item_names(storage)
+                ));
+            }
+        } else {
+            items.push(storage_item(meta, pallet, item)?);
+        }
+    }
+    Ok(items)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_item_patterns() {
+        assert_eq!(
+            parse_item_pattern("System.Account").unwrap(),
+            ("System", "Account")
+        );
+        assert_eq!(
+            parse_item_pattern("Staking.Eras*").unwrap(),
+            ("Staking", "Eras*")
+        );
+        assert!(parse_item_pattern("System").is_err());
+        assert!(parse_item_pattern(".Account").is_err());
+        assert!(parse_item_pattern("System.").is_err());
+    }
+
+    #[test]
+    fn included_items_of_replaced_pallets_are_rejected() {
+        let items = |patterns: &[&str]| patterns.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        let replaced = ["Babe", "Staking"];
+        // `System` is excluded by default, but an item listed explicitly wins.
+        assert!(
+            check_items_not_replaced(&items(&["System.Number", "Balances.Locks"]), &replaced)
+                .is_ok()
+        );
+        let err =
+            check_items_not_replaced(&items(&["Balances.Locks", "Staking.Ledger"]), &replaced)
+                .unwrap_err();
+        assert!(err.to_string().contains("`Staking`"), "{err}");
+        assert!(check_items_not_replaced(&items(&["Staking.Ledger"]), &[]).is_ok());
+    }
+}
@@ -0,0 +1,275 @@
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use color_eyre::{eyre::eyre, Result};
+use console::style;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::process::{Child, Command};
+
+use crate::cli::{NodeArgs, RunArgs};
+use crate::{ApiClient, CreditcoinConfig};
+
+/// Log lines kept to show when the node fails.
+const LOG_TAIL: usize = 30;
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Whether a node log line reports a panic or a failed migration.
+fn is_problem(line: &str) -> bool {
+    let lower = line.to_ascii_lowercase();
+    lower.contains("panicked at")
+        || lower.contains("wasm trap")
+        || (line.contains(" ERROR ") && ["migration", "upgrade"].iter().any(|w| lower.contains(w)))
+}
+
+#[derive(Default)]
+struct NodeLog {
+    tail: VecDeque<String>,
+    problems: Vec<String>,
+    /// Print lines as they arrive.
+    echo: bool,
+}
+
+/// A node running a fork's chain-spec in a temporary base path.
+pub struct LocalNode {
+    child: Child,
+    base_path: PathBuf,
+    rpc_port: u16,
+    deadline: Instant,
+    log: Arc<Mutex<NodeLog>>,
+}
+
+impl LocalNode {
+    pub fn start(args: &NodeArgs, spec: &Path) -> Result<Self> {
+        let base_path =
+            std::env::temp_dir().join(format!("creditcoin-fork-{}", std::process::id()));
+        println!(
+            "Starting {} on {} (base path {})",
+            args.binary.display(),
+            spec.display(),
+            base_path.display()
+        );
+        let mut child = Command::new(&args.binary)
+            .arg("--chain")
+            .arg(spec)
+            .arg("--base-path")
+            .arg(&base_path)
+            .arg("--validator")
+            .arg(format!("--{}", args.authority))
+            .arg("--rpc-port")
+            .arg(args.rpc_port.to_string())
+            .args(args.node_args.iter().flatten())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| eyre!("failed to start {}: {e}", args.binary.display()))?;
+
+        let log = Arc::new(Mutex::new(NodeLog::default()));
+        if let Some(stdout) = child.stdout.take() {
+            tokio::spawn(collect_log(stdout, log.clone()));
+        }
+        if let Some(stderr) = child.stderr.take() {
+            tokio::spawn(collect_log(stderr, log.clone()));
+        }
+        Ok(Self {
+            child,
+            base_path,
+            rpc_port: args.rpc_port,
+            deadline: Instant::now() + Duration::from_secs(args.timeout),
+            log,
+        })
+    }
+
+    pub fn rpc_url(&self) -> String {
+        format!("ws://127.0.0.1:{}", self.rpc_port)
+    }
+
+    /// Fail if the node has exited or the timeout has passed.
+    fn check_alive(&mut self) -> Result<()> {
+        if let Some(status) = self.child.try_wait()? {
+            return Err(self.failure(&format!("node exited with {status}")));
+        }
+        if Instant::now() > self.deadline {
+            return Err(self.failure("timed out waiting for the node"));
+        }
+        Ok(())
+    }
+
+    /// An error for `what`, after printing the end of the node's log.
+    fn failure(&self, what: &str) -> color_eyre::Report {
+        let log = self.log.lock().expect("log lock");
+        if !log.echo {
+            eprintln!("{}", style("Last node log lines:").yellow());
+            for line in &log.tail {
+                eprintln!("  {line}");
+            }
+        }
+        eyre!("{what}")
+    }
+
+    /// Connect to the node's RPC once it is up.
+    pub async fn connect(&mut self) -> Result<ApiClient> {
+        loop {
+            self.check_alive()?;
+            match ApiClient::<CreditcoinConfig>::from_url(self.rpc_url()).await {
+                Ok(api) => return Ok(api),
+                Err(_) => tokio::time::sleep(POLL_INTERVAL).await,
+            }
+        }
+    }
+
+    /// Wait until block `number` is finalized, returning the best block then.
+    pub async fn wait_for_finalized(&mut self, api: &ApiClient, number: u32) -> Result<u32> {
+        let mut last = None;
+        loop {
+            self.check_alive()?;
+            let best = api
+                .rpc()
+                .header(None)
+                .await?
+                .map_or(0, |header| header.number);
+            let finalized_hash = api.rpc().finalized_head().await?;
+            let finalized = api
+                .rpc()
+                .header(Some(finalized_hash))
+                .await?
+                .map_or(0, |header| header.number);
+            if last != Some((best, finalized)) {
+                println!("best #{best}, finalized #{finalized}");
+                last = Some((best, finalized));
+            }
+            if finalized >= number {
+                return Ok(best);
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    /// Panics and migration errors logged so far.
+    pub fn problems(&self) -> Vec<String> {
+        self.log.lock().expect("log lock").problems.clone()
+    }
+
+    /// Keep the node running, echoing its log, until it exits or Ctrl-C.
+    async fn keep_running(mut self) -> Result<()> {
+        println!(
+            "{}",
+            style(format!(
+                "Node keeps running at {} (Ctrl-C to stop)",
+                self.rpc_url()
+            ))
+            .green()
+        );
+        self.log.lock().expect("log lock").echo = true;
+        let status: Option<ExitStatus> = tokio::select! {
+            status = self.child.wait() => Some(status?),
+            signal = tokio::signal::ctrl_c() => {
+                signal?;
+                None
+            }
+        };
+        self.stop().await?;
+        match status {
+            Some(status) => Err(eyre!("node exited with {status}")),
+            None => Ok(()),
+        }
+    }
+
+    /// With `keep_running`, keep the node running if it still is; otherwise
+    /// stop it.
+    pub async fn finish(mut self, keep_running: bool) -> Result<()> {
+        if keep_running && self.child.try_wait()?.is_none() {
+            self.keep_running().await
+        } else {
+            self.stop().await
+        }
+    }
+
+    /// Stop the node and remove its base path.
+    pub async fn stop(mut self) -> Result<()> {
+        if self.child.try_wait()?.is_none() {
+            self.child.kill().await?;
+        }
+        if self.base_path.exists() {
+            tokio::fs::remove_dir_all(&self.base_path).await?;
+        }
+        Ok(())
+    }
+}
+
+async fn collect_log(stream: impl AsyncRead + Unpin, log: Arc<Mutex<NodeLog>>) {
+    let mut lines = BufReader::new(stream).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let mut log = log.lock().expect("log lock");
+        if log.echo {
+            eprintln!("{line}");
+        }
+        if is_problem(&line) {
+            log.problems.push(line.clone());
+        }
+        if log.tail.len() == LOG_TAIL {
+            log.tail.pop_front();
+        }
+        log.tail.push_back(line);
+    }
+}
+
+/// Print the panics and migration errors a node logged; `true` if there
+/// were none.
+pub fn report_problems(node: &LocalNode) -> bool {
+    let problems = node.problems();
+    for line in &problems {
+        println!("{} {line}", style("node log:").red());
+    }
+    problems.is_empty()
+}
+
+/// Boot the fork with the local node, wait for `--blocks` blocks to be
+/// finalized and check its log for panics and migration errors.
+pub async fn run(args: &RunArgs) -> Result<()> {
+    let mut node = LocalNode::start(&args.node, &args.spec)?;
+    let waited = async {
+        let api = node.connect().await?;
+        let best = node.wait_for_finalized(&api, args.blocks).await?;
+        println!("Produced {best} block(s), finalized {}", args.blocks);
+        Ok::<_, color_eyre::Report>(())
+    }
+    .await;
+    let clean = report_problems(&node);
+    let outcome = waited.and_then(|()| {
+        if clean {
+            Ok(())
+        } else {
+            Err(eyre!("the node logged panics or migration errors"))
+        }
+    });
+
+    match &outcome {
+        Ok(()) => println!("{}", style("Fork runs").green()),
+        Err(e) => println!("{} {e}", style("Fork failed:").red()),
+    }
+    node.finish(args.keep_running).await?;
+    outcome
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn panics_and_migration_errors_are_problems() {
+        assert!(is_problem(
+            "Thread 'tokio-runtime-worker' panicked at 'attempt to add with overflow'"
+        ));
+        assert!(is_problem(
+            "2024-01-01 00:00:00 ERROR runtime::staking: Migration to v14 failed"
+        ));
+        assert!(!is_problem(
+            "2024-01-01 00:00:00  INFO runtime::staking: Migration to v14 done"
+        ));
+        assert!(!is_problem("2024-01-01 00:00:00 ERROR sync: Bad peer"));
+    }
+}
@@ -0,0 +1,321 @@
+use std::collections::HashSet;
+
+use color_eyre::{eyre::eyre, Result};
+use frame_metadata::{RuntimeMetadataV14, StorageEntryType};
+use serde::Deserialize;
+use serde_json::Value as JsonValue;
+
+use crate::cli::Cli;
+use crate::metadata;
+use crate::scale::encode_json;
+use crate::{module_prefix, ToHex};
+
+/// An `--overrides-file`: the JSON counterpart of `--set`, `--remove` and
+/// `--remove-prefix`.
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct OverridesFile {
+    #[serde(default)]
+    set: serde_json::Map<String, JsonValue>,
+    #[serde(default)]
+    set_typed: serde_json::Map<String, JsonValue>,
+    #[serde(default)]
+    remove: Vec<String>,
+    #[serde(default)]
+    remove_prefix: Vec<String>,
+}
+
+/// User-requested storage surgery, before keys are resolved.
+#[derive(Default)]
+pub struct RawOverrideArgs {
+    set: Vec<(String, String)>,
+    /// `Pallet.Item[args]` targets with the JSON values to encode for them.
+    set_typed: Vec<(String, JsonValue)>,
+    remove: Vec<String>,
+    remove_prefix: Vec<String>,
+}
+
+impl RawOverrideArgs {
+    /// Gather `--set`/`--set-typed`/`--remove`/`--remove-prefix` and the
+    /// entries of `--overrides-file`; flags are applied after the file's entries.
+    pub fn from_cli(cli: &Cli) -> Result<Self> {
+        let mut args = Self::default();
+        if let Some(path) = &cli.overrides_file {
+            let file = std::fs::File::open(path)
+                .map_err(|e| eyre!("failed to open {}: {e}", path.display()))?;
+            let parsed: OverridesFile = serde_json::from_reader(std::io::BufReader::new(file))
+                .map_err(|e| eyre!("invalid overrides file {}: {e}", path.display()))?;
+            for (key, value) in parsed.set {
+                let value = value.as_str().ok_or_else(|| {
+                    eyre!("{}: value for `{key}` must be a hex string", path.display())
+                })?;
+                args.set.push((key, value.to_owned()));
+            }
+            args.set_typed.extend(parsed.set_typed);
+            args.remove.extend(parsed.remove);
+            args.remove_prefix.extend(parsed.remove_prefix);
+        }
+        for entry in cli.set.iter().flatten() {
+            let (key, value) = entry
+                .split_once('=')
+                .ok_or_else(|| eyre!("invalid --set `{entry}`: expected `<key>=<value>`"))?;
+            args.set
+                .push((key.trim().to_owned(), value.trim().to_owned()));
+        }
+        for entry in cli.set_typed.iter().flatten() {
+            let (target, value) = split_typed_override(entry)
+                .map_err(|e| eyre!("invalid --set-typed `{entry}`: {e}"))?;
+            let value = serde_json::from_str(value)
+                .map_err(|e| eyre!("invalid --set-typed `{entry}`: bad JSON value: {e}"))?;
+            args.set_typed.push((target.to_owned(), value));
+        }
+        args.remove.extend(cli.remove.iter().flatten().cloned());
+        args.remove_prefix
+            .extend(cli.remove_prefix.iter().flatten().cloned());
+        Ok(args)
+    }
+
+    /// Whether any key is given by name and so needs the runtime metadata.
+    pub fn needs_metadata(&self) -> bool {
+        self.set
+            .iter()
+            .map(|(k, _)| k)
+            .chain(&self.remove)
+            .chain(&self.remove_prefix)
+            .any(|k| !is_hex(k))
+            || !self.set_typed.is_empty()
+    }
+
+    pub fn resolve(self, meta: Option<&RuntimeMetadataV14>) -> Result<RawOverrides> {
+        let mut set = self
+            .set
+            .into_iter()
+            .map(|(key, value)| Ok((resolve_value_key(&key, meta)?, normalize_hex(&value)?)))
+            .collect::<Result<Vec<_>>>()?;
+        for (target, value) in &self.set_typed {
+            let resolved = resolve_typed_override(target, value, meta)
+                .map_err(|e| eyre!("typed override `{target}`: {e}"))?;
+            set.push(resolved);
+        }
+        let remove = self
+            .remove
+            .iter()
+            .map(|key| resolve_value_key(key, meta))
+            .collect::<Result<_>>()?;
+        let remove_prefix = self
+            .remove_prefix
+            .iter()
+            .map(|key| resolve_prefix(key, meta))
+            .collect::<Result<_>>()?;
+        Ok(RawOverrides {
+            set,
+            remove,
+            remove_prefix,
+        })
+    }
+}
+
+/// Raw storage surgery with every key resolved to hex.
+#[derive(Default)]
+pub struct RawOverrides {
+    pub set: Vec<(String, String)>,
+    pub remove: HashSet<String>,
+    pub remove_prefix: Vec<String>,
+}
+
+impl RawOverrides {
+    /// Apply to the final override map: explicit removals drop the tool's own
+    /// overrides too, and explicit values win over everything.
+    pub fn apply(&self, overrides: &mut serde_json::Map<String, JsonValue>) {
+        overrides.retain(|key, _| {
+            !self.remove.contains(key) && !self.remove_prefix.iter().any(|p| key.starts_with(p))
+        });
+        for (key, value) in &self.set {
+            overrides.insert(key.clone(), value.clone().into());
+        }
+    }
+}
+
+fn is_hex(s: &str) -> bool {
+    s.starts_with("0x")
+}
+
+/// Lowercase `0x`-prefixed hex, as storage keys appear in specs and the cache.
+fn normalize_hex(s: &str) -> Result<String> {
+    let digits = s
+        .strip_prefix("0x")
+        .ok_or_else(|| eyre!("`{s}` is not 0x-prefixed hex"))?;
+    let bytes = hex::decode(digits).map_err(|e| eyre!("`{s}` is not valid hex: {e}"))?;
+    Ok(bytes.to_hex())
+}
+
+fn require_metadata<'a>(
+    meta: Option<&'a RuntimeMetadataV14>,
+    key: &str,
+) -> Result<&'a RuntimeMetadataV14> {
+    meta.ok_or_else(|| eyre!("resolving `{key}` needs the runtime metadata"))
+}
+
+/// The key of a single value: hex, or the `Pallet.Item` name of a plain
+/// (non-map) storage item.
+pub fn resolve_value_key(key: &str, meta: Option<&RuntimeMetadataV14>) -> Result<String> {
+    if is_hex(key) {
+        return normalize_hex(key);
+    }
+    let (pallet, item) = metadata::parse_item_pattern(key)?;
+    let found = metadata::storage_item(require_metadata(meta, key)?, pallet, item)?;
+    if let StorageEntryType::Map { .. } = found.entry.ty {
+        return Err(eyre!(
+            "`{key}` is a storage map; give the full hex key (or use --remove-prefix)"
+        ));
+    }
+    Ok(found.prefix())
+}
+
+/// A key prefix: hex, `Pallet.Item` (every key of the item) or `Pallet`
+/// (every key of the pallet).
+pub fn resolve_prefix(key: &str, meta: Option<&RuntimeMetadataV14>) -> Result<String> {
+    if is_hex(key) {
+        return normalize_hex(key);
+    }
+    let meta = require_metadata(meta, key)?;
+    if key.contains('.') {
+        let (pallet, item) = metadata::parse_item_pattern(key)?;
+        Ok(metadata::storage_item(meta, pallet, item)?.prefix())
+    } else {
+        let storage = metadata::pallet_storage(meta, key)?;
+        Ok(module_prefix(&storage.prefix))
+    }
+}
+
+/// Split `Pallet.Item[args]=<json>` into its target and JSON value text.
+/// The bracketed arguments are a JSON array, so they may contain `=`.
+fn split_typed_override(spec: &str) -> Result<(&str, &str)> {
+    let target_end = match spec.find(|c| c == '[' || c == '=') {
+        Some(i) if spec[i..].starts_with('[') => {
+            let mut args =
+                serde_json::Deserializer::from_str(&spec[i..]).into_iter::<Vec<JsonValue>>();
+            args.next()
+                .ok_or_else(|| eyre!("missing key arguments"))?
+                .map_err(|e| eyre!("bad key arguments: {e}"))?;
+            i + args.byte_offset()
+        }
+        Some(i) => i,
+        None => spec.len(),
+    };
+    let (target, rest) = spec.split_at(target_end);
+    let value = rest
+        .trim_start()
+        .strip_prefix('=')
+        .ok_or_else(|| eyre!("expected `Pallet.Item[key args]=<JSON value>`"))?;
+    Ok((target.trim(), value))
+}
+
+/// Parse `Pallet.Item` or `Pallet.Item[arg, ...]` into the item name and its
+/// JSON key arguments.
+fn parse_typed_target(target: &str) -> Result<(&str, Vec<JsonValue>)> {
+    match target.find('[') {
+        Some(i) => {
+            let args =
+                serde_json::from_str(&target[i..]).map_err(|e| eyre!("bad key arguments: {e}"))?;
+            Ok((target[..i].trim(), args))
+        }
+        None => Ok((target, Vec::new())),
+    }
+}
+
+/// Compute the storage key of a typed override with the item's hashers and
+/// SCALE-encode its value against the metadata's type registry.
+fn resolve_typed_override(
+    target: &str,
+    value: &JsonValue,
+    meta: Option<&RuntimeMetadataV14>,
+) -> Result<(String, String)> {
+    let meta = require_metadata(meta, target)?;
+    let (name, args) = parse_typed_target(target)?;
+    let (pallet, item) = metadata::parse_item_pattern(name)?;
+    let item = metadata::storage_item(meta, pallet, item)?;
+    let key = item.key(&meta.types, &args)?;
+    let mut encoded = Vec::new();
+    encode_json(&meta.types, item.value_type(), value, &mut encoded)?;
+    Ok((key, encoded.to_hex()))
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    fn args(flags: &[&str]) -> Result<RawOverrideArgs> {
+        let argv = ["creditcoin-fork", "--bin", "node", "--orig", "main"];
+        RawOverrideArgs::from_cli(&Cli::parse_from(argv.iter().chain(flags)))
+    }
+
+    #[test]
+    fn hex_keys_resolve_without_metadata() {
+        let args = args(&[
+            "--set",
+            "0xAB12=0x01",
+            "--remove",
+            "0xcd",
+            "--remove-prefix",
+            "0xEF",
+        ])
+        .unwrap();
+        assert!(!args.needs_metadata());
+        let resolved = args.resolve(None).unwrap();
+        assert_eq!(resolved.set, vec![("0xab12".to_owned(), "0x01".to_owned())]);
+        assert!(resolved.remove.contains("0xcd"));
+        assert_eq!(resolved.remove_prefix, vec!["0xef".to_owned()]);
+    }
+
+    #[test]
+    fn named_keys_need_metadata() {
+        let named = args(&["--set", "Sudo.Key=0x00"]).unwrap();
+        assert!(named.needs_metadata());
+        assert!(named.resolve(None).is_err());
+        assert!(args(&["--set-typed", "Sudo.Key=null"])
+            .unwrap()
+            .needs_metadata());
+    }
+
+    #[test]
+    fn rejects_malformed_entries() {
+        assert!(args(&["--set", "0xab"]).is_err());
+        assert!(args(&["--set", "0xab=zz"]).unwrap().resolve(None).is_err());
+        assert!(args(&["--set-typed", "System.Account[\"5Grw\"]"]).is_err());
+        assert!(args(&["--set-typed", "Balances.TotalIssuance={"]).is_err());
+    }
+
+    #[test]
+    fn splits_typed_overrides() {
+        assert_eq!(
+            split_typed_override("Balances.TotalIssuance = \"100\"").unwrap(),
+            ("Balances.TotalIssuance", " \"100\"")
+        );
+        let (target, value) =
+            split_typed_override(r#"Attestation.Attestors[3, "a=b"]={"x": 1}"#).unwrap();
+        assert_eq!(target, r#"Attestation.Attestors[3, "a=b"]"#);
+        assert_eq!(value, r#"{"x": 1}"#);
+        let (name, args) = parse_typed_target(target).unwrap();
+        assert_eq!(name, "Attestation.Attestors");
+        assert_eq!(args, vec![JsonValue::from(3), JsonValue::from("a=b")]);
+    }
+
+    #[test]
+    fn explicit_overrides_win() {
+        let mut overrides = serde_json::Map::new();
+        overrides.insert("0xaa01".to_owned(), "0x01".into());
+        overrides.insert("0xbb".to_owned(), "0x02".into());
+        overrides.insert("0xcc".to_owned(), "0x03".into());
+        let raw = RawOverrides {
+            set: vec![("0xcc".to_owned(), "0x04".to_owned())],
+            remove: HashSet::from(["0xbb".to_owned()]),
+            remove_prefix: vec!["0xaa".to_owned()],
+        };
+        raw.apply(&mut overrides);
+        assert_eq!(overrides.len(), 1);
+        assert_eq!(overrides["0xcc"], "0x04");
+    }
+}
@@ -0,0 +1,114 @@
+use std::fs::File;
+use std::io::{BufWriter, Write as _};
+use std::path::{Path, PathBuf};
+
+use color_eyre::{eyre::eyre, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::cli::BlockRef;
+
+/// Where a fork's state and runtime came from, written next to its
+/// chain-spec as `<out>.provenance.json`.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Provenance {
+    /// The original chain's id.
+    pub original: String,
+    pub rpc: String,
+    /// The block the state was fetched at (by this run, or recorded with the
+    /// reused storage file).
+    pub state_at: Option<String>,
+    pub runtime: RuntimeSource,
+    /// `spec_name` and `spec_version` of the fork's runtime.
+    pub runtime_version: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase", tag = "source")]
+pub enum RuntimeSource {
+    /// The `:code` of the fetched state.
+    State,
+    /// A `--runtime` file.
+    File { path: PathBuf },
+    /// The `:code` at a `--runtime-at` block.
+    Block { block: String, hash: String },
+}
+
+impl Provenance {
+    pub fn path(out: &Path) -> PathBuf {
+        let mut path = out.to_owned().into_os_string();
+        path.push(".provenance.json");
+        PathBuf::from(path)
+    }
+
+    pub fn write(&self, out: &Path) -> Result<()> {
+        let mut writer = BufWriter::new(File::create(Self::path(out))?);
+        serde_json::to_writer_pretty(&mut writer, self)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// The block a storage file was fetched at, written next to it as
+/// `<storage>.meta` so a run reusing the file decodes it with that block's
+/// metadata.
+#[derive(Serialize, Deserialize)]
+pub struct StateRecord {
+    /// `--at` as given.
+    pub at: String,
+    /// The hash it resolved to.
+    pub hash: String,
+}
+
+impl StateRecord {
+    pub fn path(storage: &Path) -> PathBuf {
+        let mut path = storage.to_owned().into_os_string();
+        path.push(".meta");
+        PathBuf::from(path)
+    }
+
+    /// The record of the storage file at `storage`, if it has one.
+    pub fn read(storage: &Path) -> Result<Option<Self>> {
+        let path = Self::path(storage);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let file = File::open(&path).map_err(|e| eyre!("{}: {e}", path.display()))?;
+        serde_json::from_reader(std::io::BufReader::new(file))
+            .map(Some)
+            .map_err(|e| eyre!("{}: {e}", path.display()))
+    }
+
+    pub fn write(&self, storage: &Path) -> Result<()> {
+        let mut writer = BufWriter::new(File::create(Self::path(storage))?);
+        serde_json::to_writer_pretty(&mut writer, self)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Whether `at` names the recorded block: its hash, or the same `--at`.
+    pub fn matches(&self, at: &BlockRef) -> bool {
+        match at {
+            BlockRef::Hash(hash) => format!("{hash:?}") == self.hash,
+            other => other.to_string() == self.at,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recorded_blocks_match_their_hash_or_at() {
+        let hash = format!("0x{}", "ab".repeat(32));
+        let record = StateRecord {
+            at: "finalized-10".to_owned(),
+            hash: hash.clone(),
+        };
+        assert!(record.matches(&"finalized-10".parse().unwrap()));
+        assert!(record.matches(&hash.parse().unwrap()));
+        assert!(!record.matches(&BlockRef::Finalized));
+        assert!(!record.matches(&format!("0x{}", "cd".repeat(32)).parse().unwrap()));
+    }
+}
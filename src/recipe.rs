@@ -0,0 +1,450 @@
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use clap::parser::ValueSource;
+use clap::ArgMatches;
+use color_eyre::{eyre::eyre, Result};
+use serde::Deserialize;
+
+use crate::cli::{BlockRef, Cli, StorageFile, CHAIN_TYPES};
+use crate::Chain;
+
+/// A fork recipe loaded with `--config`: a TOML document whose keys are the
+/// long flag names (`bin`, `orig`, `exclude-pallets`, ...). Flags passed on the
+/// command line win over the recipe's values, including the ones they
+/// conflict with (`--sudo` over `no-sudo = true`), and `--no-<switch>` turns
+/// off a switch the recipe turns on (e.g. `--no-usc` for `usc = true`). Paths
+/// are relative to the current directory, as they are for flags.
+#[derive(Deserialize, Default, Debug)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Recipe {
+    bin: Option<PathBuf>,
+    runtime: Option<PathBuf>,
+    runtime_at: Option<String>,
+    code_substitute: Option<Vec<String>>,
+    clear_code_substitutes: Option<bool>,
+    compress_runtime: Option<bool>,
+    max_code_size: Option<usize>,
+    allow_runtime_mismatch: Option<bool>,
+    out: Option<PathBuf>,
+    orig: Option<String>,
+    base: Option<String>,
+    storage: Option<String>,
+    at: Option<String>,
+    name: Option<String>,
+    id: Option<String>,
+    protocol_id: Option<String>,
+    allow_original_network: Option<bool>,
+    token_symbol: Option<String>,
+    token_decimals: Option<u8>,
+    ss58_format: Option<u16>,
+    chain_type: Option<String>,
+    telemetry_endpoint: Option<Vec<String>>,
+    boot_node: Option<Vec<String>>,
+    extension: Option<Vec<String>>,
+    rpc: Option<String>,
+    http_rpc: Option<String>,
+    rpc_connections: Option<usize>,
+    value_batch_size: Option<usize>,
+    key_scan_concurrency: Option<usize>,
+    pallets: Option<Vec<String>>,
+    exclude_pallets: Option<Vec<String>>,
+    no_default_excludes: Option<bool>,
+    include_items: Option<Vec<String>>,
+    exclude_items: Option<Vec<String>>,
+    set: Option<Vec<String>>,
+    set_typed: Option<Vec<String>>,
+    remove: Option<Vec<String>>,
+    remove_prefix: Option<Vec<String>>,
+    overrides_file: Option<PathBuf>,
+    fund: Option<Vec<String>>,
+    fund_file: Option<PathBuf>,
+    report_orphaned_locks: Option<bool>,
+    remove_orphaned_locks: Option<bool>,
+    reconcile_issuance: Option<bool>,
+    verify: Option<bool>,
+    impersonate: Option<Vec<String>>,
+    sudo: Option<String>,
+    no_sudo: Option<bool>,
+    keep_validators: Option<bool>,
+    preserve_stakers: Option<bool>,
+    validators: Option<Vec<String>>,
+    validators_file: Option<PathBuf>,
+    validator_stake: Option<String>,
+    usc: Option<bool>,
+    usc_chain_key: Option<u64>,
+}
+
+impl Recipe {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| eyre!("failed to read recipe {}: {e}", path.display()))?;
+        Self::from_toml(&text).map_err(|e| eyre!("invalid recipe {}: {e}", path.display()))
+    }
+
+    pub fn from_toml(text: &str) -> Result<Self> {
+        let recipe: Self = toml::from_str(text)?;
+        recipe.validate()?;
+        Ok(recipe)
+    }
+
+    fn validate(&self) -> Result<()> {
+        for (key, value) in [
+            ("rpc-connections", self.rpc_connections),
+            ("value-batch-size", self.value_batch_size),
+            ("key-scan-concurrency", self.key_scan_concurrency),
+        ] {
+            if value == Some(0) {
+                return Err(eyre!("`{key}` must be at least 1"));
+            }
+        }
+        if let Some(block) = &self.runtime_at {
+            block
+                .parse::<BlockRef>()
+                .map_err(|e| eyre!("`runtime-at`: {e}"))?;
+        }
+        if let Some(at) = &self.at {
+            at.parse::<BlockRef>().map_err(|e| eyre!("`at`: {e}"))?;
+        }
+        if let Some(chain_type) = &self.chain_type {
+            if !CHAIN_TYPES.contains(&chain_type.as_str()) {
+                return Err(eyre!(
+                    "`chain-type` must be one of {}, got `{chain_type}`",
+                    CHAIN_TYPES.join(", ")
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Fill in every `cli` field that was not given on the command line.
+    pub fn apply(self, cli: &mut Cli, matches: &ArgMatches) -> Result<()> {
+        let unset = |id: &str| matches.value_source(id) != Some(ValueSource::CommandLine);
+
+        macro_rules! merge {
+            ($field:ident, $value:expr) => {
+                if unset(stringify!($field)) {
+                    if let Some(value) = $value {
+                        cli.$field = value;
+                    }
+                }
+            };
+        }
+        // A switch the command line turns off with `--no-<switch>`.
+        macro_rules! merge_switch {
+            ($field:ident, $off:ident, $value:expr) => {
+                if unset(stringify!($off)) {
+                    merge!($field, $value);
+                }
+            };
+        }
+
+        merge!(binary, self.bin.map(Some));
+        merge!(runtime, self.runtime.map(Some));
+        merge!(
+            runtime_at,
+            self.runtime_at
+                .map(|s| s.parse::<BlockRef>())
+                .transpose()?
+                .map(Some)
+        );
+        merge!(code_substitute, self.code_substitute.map(Some));
+        merge_switch!(
+            clear_code_substitutes,
+            no_clear_code_substitutes,
+            self.clear_code_substitutes
+        );
+        merge_switch!(compress_runtime, no_compress_runtime, self.compress_runtime);
+        merge!(max_code_size, self.max_code_size);
+        merge_switch!(
+            allow_runtime_mismatch,
+            no_allow_runtime_mismatch,
+            self.allow_runtime_mismatch
+        );
+        merge!(out, self.out);
+        merge!(
+            original_chain,
+            self.orig.map(|s| s.parse::<Chain>()).transpose()?.map(Some)
+        );
+        merge!(
+            base_chain,
+            self.base.map(|s| s.parse::<Chain>()).transpose()?
+        );
+        merge!(
+            storage,
+            self.storage
+                .map(|s| Some(StorageFile::from_str(&s).unwrap_or_else(|e| match e {})))
+        );
+        merge!(
+            at,
+            self.at
+                .map(|s| s.parse::<BlockRef>())
+                .transpose()?
+                .map(Some)
+        );
+        merge!(name, self.name.map(Some));
+        merge!(id, self.id.map(Some));
+        merge!(protocol_id, self.protocol_id.map(Some));
+        merge_switch!(
+            allow_original_network,
+            no_allow_original_network,
+            self.allow_original_network
+        );
+        merge!(token_symbol, self.token_symbol.map(Some));
+        merge!(token_decimals, self.token_decimals.map(Some));
+        merge!(ss58_format, self.ss58_format.map(Some));
+        merge!(chain_type, self.chain_type.map(Some));
+        merge!(telemetry_endpoint, self.telemetry_endpoint.map(Some));
+        merge!(boot_node, self.boot_node.map(Some));
+        merge!(extension, self.extension.map(Some));
+        merge!(rpc, self.rpc);
+        merge!(http_rpc, self.http_rpc.map(Some));
+        merge!(rpc_connections, self.rpc_connections);
+        merge!(value_batch_size, self.value_batch_size);
+        merge!(key_scan_concurrency, self.key_scan_concurrency);
+        merge!(pallets, self.pallets.map(Some));
+        merge!(exclude_pallets, self.exclude_pallets.map(Some));
+        merge_switch!(
+            no_default_excludes,
+            default_excludes,
+            self.no_default_excludes
+        );
+        merge!(include_items, self.include_items.map(Some));
+        merge!(exclude_items, self.exclude_items.map(Some));
+        merge!(set, self.set.map(Some));
+        merge!(set_typed, self.set_typed.map(Some));
+        merge!(remove, self.remove.map(Some));
+        merge!(remove_prefix, self.remove_prefix.map(Some));
+        merge!(overrides_file, self.overrides_file.map(Some));
+        merge!(fund, self.fund.map(Some));
+        merge!(fund_file, self.fund_file.map(Some));
+        merge_switch!(
+            report_orphaned_locks,
+            no_report_orphaned_locks,
+            self.report_orphaned_locks
+        );
+        merge_switch!(
+            remove_orphaned_locks,
+            no_remove_orphaned_locks,
+            self.remove_orphaned_locks
+        );
+        merge_switch!(
+            reconcile_issuance,
+            no_reconcile_issuance,
+            self.reconcile_issuance
+        );
+        merge_switch!(verify, no_verify, self.verify);
+        merge!(impersonate, self.impersonate.map(Some));
+        merge!(sudo, self.sudo.map(Some));
+        merge!(no_sudo, self.no_sudo);
+        merge_switch!(keep_validators, no_keep_validators, self.keep_validators);
+        merge_switch!(preserve_stakers, no_preserve_stakers, self.preserve_stakers);
+        merge!(validators, self.validators.map(Some));
+        merge!(validators_file, self.validators_file.map(Some));
+        merge!(validator_stake, self.validator_stake);
+        merge_switch!(usc, no_usc, self.usc);
+        merge!(usc_chain_key, self.usc_chain_key);
+
+        // A value given on the command line drops the recipe's conflicting one.
+        macro_rules! yield_to_cli {
+            ($given:ident, $field:ident = $cleared:expr) => {
+                if !unset(stringify!($given)) && unset(stringify!($field)) {
+                    cli.$field = $cleared;
+                }
+            };
+        }
+        yield_to_cli!(runtime, runtime_at = None);
+        yield_to_cli!(runtime_at, runtime = None);
+        yield_to_cli!(sudo, no_sudo = false);
+        yield_to_cli!(no_sudo, sudo = None);
+        yield_to_cli!(validators, keep_validators = false);
+        yield_to_cli!(validators_file, keep_validators = false);
+        yield_to_cli!(preserve_stakers, keep_validators = false);
+        yield_to_cli!(keep_validators, validators = None);
+        yield_to_cli!(keep_validators, validators_file = None);
+        yield_to_cli!(keep_validators, preserve_stakers = false);
+
+        check_conflicts(cli)
+    }
+}
+
+/// Re-check the flag conflicts clap enforces on the command line, which
+/// values merged from a recipe bypass.
+fn check_conflicts(cli: &Cli) -> Result<()> {
+    let conflicts = [
+        (
+            "runtime",
+            cli.runtime.is_some(),
+            "runtime-at",
+            cli.runtime_at.is_some(),
+        ),
+        ("sudo", cli.sudo.is_some(), "no-sudo", cli.no_sudo),
+        (
+            "validators",
+            cli.validators.is_some(),
+            "keep-validators",
+            cli.keep_validators,
+        ),
+        (
+            "validators-file",
+            cli.validators_file.is_some(),
+            "keep-validators",
+            cli.keep_validators,
+        ),
+        (
+            "preserve-stakers",
+            cli.preserve_stakers,
+            "keep-validators",
+            cli.keep_validators,
+        ),
+    ];
+    for (a, a_set, b, b_set) in conflicts {
+        if a_set && b_set {
+            return Err(eyre!(
+                "`{a}` conflicts with `{b}` (set in the recipe or on the command line)"
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::{CommandFactory, FromArgMatches};
+
+    use super::*;
+
+    const RECIPE: &str = r#"
+        bin = "creditcoin3-node"
+        orig = "testnet"
+        rpc = "wss://rpc.example.com"
+        exclude-pallets = ["Identity"]
+        usc = true
+        usc-chain-key = 5
+    "#;
+
+    #[test]
+    fn command_line_flags_win_over_the_recipe() {
+        let matches = Cli::command()
+            .try_get_matches_from(["creditcoin-fork", "--config", "fork.toml", "--orig", "main"])
+            .unwrap();
+        let mut cli = Cli::from_arg_matches(&matches).unwrap();
+        Recipe::from_toml(RECIPE)
+            .unwrap()
+            .apply(&mut cli, &matches)
+            .unwrap();
+
+        assert_eq!(cli.binary, Some(PathBuf::from("creditcoin3-node")));
+        assert_eq!(cli.original_chain, Some(Chain::Other("main".into())));
+        assert_eq!(cli.rpc, "wss://rpc.example.com");
+        assert_eq!(cli.exclude_pallets, Some(vec!["Identity".to_owned()]));
+        assert!(cli.usc);
+        assert_eq!(cli.usc_chain_key, 5);
+        assert_eq!(cli.base_chain, Chain::Dev);
+        assert_eq!(cli.at, None);
+    }
+
+    #[test]
+    fn recipe_values_obey_flag_conflicts() {
+        let matches = Cli::command()
+            .try_get_matches_from(["creditcoin-fork", "--config", "fork.toml"])
+            .unwrap();
+        let mut cli = Cli::from_arg_matches(&matches).unwrap();
+        let err = Recipe::from_toml(&format!("{RECIPE}sudo = \"//Bob\"\nno-sudo = true"))
+            .unwrap()
+            .apply(&mut cli, &matches)
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("`sudo` conflicts with `no-sudo`"),
+            "{err}"
+        );
+
+        let mut cli = Cli::from_arg_matches(&matches).unwrap();
+        let recipe = format!("{RECIPE}keep-validators = true\nvalidators-file = \"v.txt\"");
+        assert!(Recipe::from_toml(&recipe)
+            .unwrap()
+            .apply(&mut cli, &matches)
+            .is_err());
+    }
+
+    #[test]
+    fn the_command_line_overrides_conflicting_recipe_values() {
+        let apply = |args: &[&str], recipe: &str| {
+            let matches = Cli::command()
+                .try_get_matches_from(
+                    ["creditcoin-fork", "--config", "fork.toml"]
+                        .iter()
+                        .chain(args),
+                )
+                .unwrap();
+            let mut cli = Cli::from_arg_matches(&matches).unwrap();
+            Recipe::from_toml(&format!("{RECIPE}{recipe}"))
+                .unwrap()
+                .apply(&mut cli, &matches)
+                .map(|()| cli)
+        };
+
+        let cli = apply(&["--sudo", "//Bob"], "no-sudo = true").unwrap();
+        assert_eq!(cli.sudo.as_deref(), Some("//Bob"));
+        assert!(!cli.no_sudo);
+
+        let cli = apply(&["--no-sudo"], "sudo = \"//Bob\"").unwrap();
+        assert!(cli.no_sudo);
+        assert_eq!(cli.sudo, None);
+
+        let cli = apply(&["--validators", "//Alice"], "keep-validators = true").unwrap();
+        assert!(!cli.keep_validators);
+
+        let cli = apply(
+            &["--keep-validators"],
+            "validators = [\"//Alice\"]\npreserve-stakers = true",
+        )
+        .unwrap();
+        assert!(cli.keep_validators);
+        assert_eq!(cli.validators, None);
+        assert!(!cli.preserve_stakers);
+
+        let cli = apply(&["--runtime-at", "best"], "runtime = \"a.wasm\"").unwrap();
+        assert_eq!(cli.runtime, None);
+        assert_eq!(cli.runtime_at, Some(BlockRef::Best));
+    }
+
+    #[test]
+    fn recipe_switches_can_be_turned_off() {
+        let matches = Cli::command()
+            .try_get_matches_from([
+                "creditcoin-fork",
+                "--config",
+                "fork.toml",
+                "--no-usc",
+                "--no-reconcile-issuance",
+                "--default-excludes",
+            ])
+            .unwrap();
+        let mut cli = Cli::from_arg_matches(&matches).unwrap();
+        let recipe =
+            format!("{RECIPE}reconcile-issuance = true\nno-default-excludes = true\nverify = true");
+        Recipe::from_toml(&recipe)
+            .unwrap()
+            .apply(&mut cli, &matches)
+            .unwrap();
+        assert!(!cli.usc);
+        assert!(!cli.reconcile_issuance);
+        assert!(!cli.no_default_excludes);
+        assert!(cli.verify);
+
+        assert!(Cli::command()
+            .try_get_matches_from(["creditcoin-fork", "--bin", "node", "--usc", "--no-usc"])
+            .is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_and_invalid_keys() {
+        let err = Recipe::from_toml("rcp = \"ws://localhost:9944\"").unwrap_err();
+        assert!(err.to_string().contains("unknown field `rcp`"), "{err}");
+        assert!(Recipe::from_toml("value-batch-size = 0").is_err());
+        assert!(Recipe::from_toml("at = \"latest\"").is_err());
+        assert!(Recipe::from_toml("at = \"finalized-10\"").is_ok());
+        assert!(Recipe::from_toml("chain-type = \"Production\"").is_err());
+    }
+}
@@ -0,0 +1,338 @@
+use std::borrow::Cow;
+use std::fmt;
+use std::path::PathBuf;
+
+use codec::Decode;
+use color_eyre::{eyre::eyre, Result};
+use sp_core::hashing::blake2_64;
+
+use crate::ToHex;
+
+/// The first bytes of a WebAssembly module.
+pub const WASM_MAGIC: &[u8] = b"\0asm";
+/// `sp_maybe_compressed_blob`'s prefix of a zstd-compressed runtime.
+pub const ZSTD_PREFIX: &[u8] = &[82, 188, 83, 118, 70, 219, 142, 5];
+/// The most a compressed runtime may decompress to (as in
+/// `sp_maybe_compressed_blob::CODE_BLOB_BOMB_LIMIT`).
+const CODE_BLOB_BOMB_LIMIT: usize = 50 * 1024 * 1024;
+
+/// Runtime APIs listed by name rather than id.
+const KNOWN_APIS: [&str; 16] = [
+    "Core",
+    "Metadata",
+    "BlockBuilder",
+    "TaggedTransactionQueue",
+    "OffchainWorkerApi",
+    "SessionKeys",
+    "BabeApi",
+    "GrandpaApi",
+    "AuthorityDiscoveryApi",
+    "AccountNonceApi",
+    "TransactionPaymentApi",
+    "TransactionPaymentCallApi",
+    "NominationPoolsApi",
+    "StakingApi",
+    "GenesisBuilder",
+    "TryRuntime",
+];
+
+/// The wasm module of a runtime blob, decompressing it if needed.
+pub fn decompress(blob: &[u8]) -> Result<Cow<'_, [u8]>> {
+    match blob.strip_prefix(ZSTD_PREFIX) {
+        Some(compressed) => zstd::bulk::decompress(compressed, CODE_BLOB_BOMB_LIMIT)
+            .map(Cow::Owned)
+            .map_err(|e| eyre!("failed to decompress the runtime: {e}")),
+        None => Ok(Cow::Borrowed(blob)),
+    }
+}
+
+/// Check that `blob` is a wasm module or a compressed runtime blob and at
+/// most `max_size` bytes, compressing a plain wasm module first with
+/// `compress`.
+pub fn prepare_blob(blob: Vec<u8>, compress: bool, max_size: usize) -> Result<Vec<u8>> {
+    let blob = if blob.starts_with(ZSTD_PREFIX) {
+        if !decompress(&blob)?.starts_with(WASM_MAGIC) {
+            return Err(eyre!("the compressed runtime is not a wasm module"));
+        }
+        blob
+    } else if !blob.starts_with(WASM_MAGIC) {
+        return Err(eyre!("not a wasm module or compressed runtime blob"));
+    } else if compress {
+        let compressed = zstd::bulk::compress(&blob, 3)
+            .map_err(|e| eyre!("failed to compress the runtime: {e}"))?;
+        [ZSTD_PREFIX, &compressed].concat()
+    } else {
+        blob
+    };
+    if blob.len() > max_size {
+        let hint = if blob.starts_with(WASM_MAGIC) {
+            "; pass --compress-runtime to compress it"
+        } else {
+            ""
+        };
+        return Err(eyre!(
+            "the runtime is {} bytes, more than --max-code-size ({max_size}){hint}",
+            blob.len()
+        ));
+    }
+    Ok(blob)
+}
+
+/// `<block>=<wasm path>`: a runtime the node runs instead of the on-chain
+/// code from block `block` on.
+pub fn parse_code_substitute(s: &str) -> Result<(u32, PathBuf)> {
+    let (block, path) = s
+        .split_once('=')
+        .ok_or_else(|| eyre!("expected `<block>=<wasm path>`, got `{s}`"))?;
+    let block = block
+        .trim()
+        .parse()
+        .map_err(|_| eyre!("`{block}` is not a block number"))?;
+    Ok((block, PathBuf::from(path.trim())))
+}
+
+fn leb128(input: &mut &[u8]) -> Option<usize> {
+    let mut value = 0usize;
+    for shift in (0..35).step_by(7) {
+        let (&byte, rest) = input.split_first()?;
+        *input = rest;
+        value |= usize::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// The contents of the custom section `name` of a wasm module.
+fn custom_section<'a>(wasm: &'a [u8], name: &str) -> Result<Option<&'a [u8]>> {
+    let mut input = wasm
+        .strip_prefix(WASM_MAGIC)
+        .and_then(|rest| rest.get(4..))
+        .ok_or_else(|| eyre!("not a wasm module"))?;
+    while let Some((&id, rest)) = input.split_first() {
+        input = rest;
+        let len = leb128(&mut input).ok_or_else(|| eyre!("malformed wasm section"))?;
+        if input.len() < len {
+            return Err(eyre!("truncated wasm section"));
+        }
+        let (mut section, rest) = input.split_at(len);
+        input = rest;
+        if id != 0 {
+            continue;
+        }
+        let name_len = leb128(&mut section).ok_or_else(|| eyre!("malformed custom section"))?;
+        if section.get(..name_len) == Some(name.as_bytes()) {
+            return Ok(Some(&section[name_len..]));
+        }
+    }
+    Ok(None)
+}
+
+/// The version a runtime embeds in its `runtime_version` and `runtime_apis`
+/// custom sections.
+#[derive(Debug, PartialEq)]
+pub struct RuntimeVersion {
+    pub spec_name: String,
+    pub impl_name: String,
+    pub spec_version: u32,
+    pub impl_version: u32,
+    pub transaction_version: Option<u32>,
+    pub apis: Vec<([u8; 8], u32)>,
+}
+
+impl RuntimeVersion {
+    /// Read the version of a (possibly compressed) runtime blob.
+    pub fn of_blob(blob: &[u8]) -> Result<Self> {
+        let wasm = decompress(blob)?;
+        let mut section = custom_section(&wasm, "runtime_version")?
+            .ok_or_else(|| eyre!("the runtime has no `runtime_version` section"))?;
+        let input = &mut section;
+        let spec_name = String::decode(input)?;
+        let impl_name = String::decode(input)?;
+        let _authoring_version = u32::decode(input)?;
+        let spec_version = u32::decode(input)?;
+        let impl_version = u32::decode(input)?;
+        let mut apis = Vec::<([u8; 8], u32)>::decode(input)?;
+        // Older runtimes end before the transaction version.
+        let transaction_version = u32::decode(input).ok();
+        // The API list lives in its own section, as raw `(id, version)` pairs.
+        if let Some(section) = custom_section(&wasm, "runtime_apis")? {
+            apis = section
+                .chunks_exact(12)
+                .map(|chunk| {
+                    let id = chunk[..8].try_into().expect("8 bytes");
+                    (
+                        id,
+                        u32::from_le_bytes(chunk[8..].try_into().expect("4 bytes")),
+                    )
+                })
+                .collect();
+        }
+        Ok(Self {
+            spec_name,
+            impl_name,
+            spec_version,
+            impl_version,
+            transaction_version,
+            apis,
+        })
+    }
+}
+
+impl fmt::Display for RuntimeVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} (impl {}) spec_version {}, impl_version {}",
+            self.spec_name, self.impl_name, self.spec_version, self.impl_version
+        )?;
+        if let Some(version) = self.transaction_version {
+            write!(f, ", transaction_version {version}")?;
+        }
+        let apis: Vec<String> = self
+            .apis
+            .iter()
+            .map(|(id, version)| {
+                let name = KNOWN_APIS
+                    .iter()
+                    .find(|name| blake2_64(name.as_bytes()) == *id)
+                    .map_or_else(|| id.to_hex(), |name| (*name).to_owned());
+                format!("{name} v{version}")
+            })
+            .collect();
+        write!(f, "\n  APIs: {}", apis.join(", "))
+    }
+}
+
+/// Problems running the fork's runtime on a chain that ran `original`, with
+/// a node whose own runtime is `node`: errors, then warnings.
+pub fn compatibility(
+    original: &RuntimeVersion,
+    fork: &RuntimeVersion,
+    node: Option<&RuntimeVersion>,
+) -> (Vec<String>, Vec<String>) {
+    let mut errors = Vec::new();
+    let mut warnings = Vec::new();
+    if fork.spec_name != original.spec_name {
+        errors.push(format!(
+            "the runtime is `{}` but the original chain runs `{}`",
+            fork.spec_name, original.spec_name
+        ));
+    }
+    if fork.spec_version < original.spec_version {
+        warnings.push(format!(
+            "the runtime's spec version {} is older than the original chain's {}",
+            fork.spec_version, original.spec_version
+        ));
+    }
+    if let Some(node) = node.filter(|node| node.spec_name != fork.spec_name) {
+        errors.push(format!(
+            "--bin is built for `{}` runtimes, not `{}`",
+            node.spec_name, fork.spec_name
+        ));
+    }
+    (errors, warnings)
+}
+
+#[cfg(test)]
+mod tests {
+    use codec::Encode;
+
+    use super::*;
+
+    fn section(id: u8, contents: &[u8]) -> Vec<u8> {
+        assert!(contents.len() < 128);
+        [&[id, contents.len() as u8][..], contents].concat()
+    }
+
+    fn custom(name: &str, payload: &[u8]) -> Vec<u8> {
+        section(
+            0,
+            &[&[name.len() as u8][..], name.as_bytes(), payload].concat(),
+        )
+    }
+
+    fn version(spec_name: &str, spec_version: u32) -> RuntimeVersion {
+        RuntimeVersion {
+            spec_name: spec_name.to_owned(),
+            impl_name: spec_name.to_owned(),
+            spec_version,
+            impl_version: 0,
+            transaction_version: Some(2),
+            apis: vec![(blake2_64(b"Core"), 4)],
+        }
+    }
+
+    #[test]
+    fn versions_are_read_from_custom_sections() {
+        let encoded = (
+            "creditcoin3",
+            "creditcoin3",
+            1u32,
+            300u32,
+            0u32,
+            Vec::<([u8; 8], u32)>::new(),
+            2u32,
+            1u8,
+        )
+            .encode();
+        let apis = [&blake2_64(b"Core")[..], &4u32.to_le_bytes()].concat();
+        let wasm = [
+            &WASM_MAGIC[..],
+            &[1, 0, 0, 0],
+            &section(1, &[0]),
+            &custom("runtime_apis", &apis),
+            &custom("runtime_version", &encoded),
+        ]
+        .concat();
+        assert_eq!(
+            RuntimeVersion::of_blob(&wasm).unwrap(),
+            version("creditcoin3", 300)
+        );
+        assert!(version("creditcoin3", 300).to_string().contains("Core v4"));
+
+        let compressed = [ZSTD_PREFIX, &zstd::bulk::compress(&wasm, 3).unwrap()].concat();
+        assert_eq!(decompress(&compressed).unwrap(), wasm);
+        assert!(RuntimeVersion::of_blob(b"not wasm").is_err());
+    }
+
+    #[test]
+    fn blobs_are_validated_compressed_and_size_checked() {
+        let wasm = [WASM_MAGIC, &[1, 0, 0, 0], &[0; 256]].concat();
+        assert_eq!(prepare_blob(wasm.clone(), false, 1024).unwrap(), wasm);
+        let compressed = prepare_blob(wasm.clone(), true, 1024).unwrap();
+        assert!(compressed.starts_with(ZSTD_PREFIX) && compressed.len() < wasm.len());
+        assert_eq!(
+            prepare_blob(compressed.clone(), true, 1024).unwrap(),
+            compressed
+        );
+        assert!(prepare_blob(wasm, false, 100).is_err());
+        assert!(prepare_blob(b"#!/bin/sh".to_vec(), true, 1024).is_err());
+    }
+
+    #[test]
+    fn code_substitutes_are_keyed_by_block_number() {
+        assert_eq!(
+            parse_code_substitute("1200=./fix.wasm").unwrap(),
+            (1200, PathBuf::from("./fix.wasm"))
+        );
+        assert!(parse_code_substitute("0xab=./fix.wasm").is_err());
+        assert!(parse_code_substitute("./fix.wasm").is_err());
+    }
+
+    #[test]
+    fn runtimes_of_other_chains_are_incompatible() {
+        let original = version("creditcoin3", 300);
+        let (errors, warnings) = compatibility(&original, &version("creditcoin3", 301), None);
+        assert!(errors.is_empty() && warnings.is_empty());
+        let (_, warnings) = compatibility(&original, &version("creditcoin3", 299), None);
+        assert_eq!(warnings.len(), 1);
+        let (errors, _) = compatibility(
+            &original,
+            &version("polkadot", 300),
+            Some(&version("polkadot", 1)),
+        );
+        assert_eq!(errors.len(), 2);
+    }
+}
@@ -0,0 +1,720 @@
+use codec::{Compact, Decode, Encode};
+use color_eyre::{eyre::eyre, Result};
+use scale_info::{form::PortableForm, Field, PortableRegistry, Type, TypeDef, TypeDefPrimitive};
+use serde_json::Value as JsonValue;
+use sp_core::crypto::{AccountId32, Ss58Codec};
+
+use crate::ToHex;
+
+/// SCALE-encode a JSON value as the registry type `type_id`.
+///
+/// The JSON shape follows the type: objects for structs with named fields,
+/// arrays for tuples and unnamed fields (a single unnamed field takes its
+/// value directly, so `AccountId32` takes a string), `"Variant"` or
+/// `{"Variant": fields}` for enums (`null` for `None`, and an `Option`'s
+/// inner value for `Some`), and numbers or decimal strings for integers
+/// (use strings past `u64`). Byte sequences and arrays also take `0x` hex,
+/// and 32-byte arrays take SS58 addresses.
+pub fn encode_json(
+    registry: &PortableRegistry,
+    type_id: u32,
+    value: &JsonValue,
+    out: &mut Vec<u8>,
+) -> Result<()> {
+    let ty = resolve(registry, type_id)?;
+    let err = |what: &str| eyre!("expected {what} for `{}`, got {value}", type_name(ty));
+
+    match ty.type_def() {
+        TypeDef::Composite(composite) => encode_fields(registry, composite.fields(), value, out)
+            .map_err(|e| eyre!("{}: {e}", type_name(ty))),
+        TypeDef::Variant(variant) => {
+            let variants = variant.variants();
+            let by_name = |name: &str| variants.iter().find(|v| v.name() == name);
+            let (chosen, fields_value) = match value {
+                JsonValue::Null => (by_name("None"), &JsonValue::Null),
+                JsonValue::String(name) if by_name(name.as_str()).is_some() => {
+                    (by_name(name.as_str()), &JsonValue::Null)
+                }
+                JsonValue::Object(map) if map.len() == 1 => {
+                    let (name, fields) = map.iter().next().expect("one entry");
+                    match by_name(name.as_str()) {
+                        Some(v) => (Some(v), fields),
+                        None => (by_name("Some"), value),
+                    }
+                }
+                _ => (by_name("Some"), value),
+            };
+            let chosen = chosen.ok_or_else(|| {
+                let names: Vec<_> = variants.iter().map(|v| v.name().as_str()).collect();
+                err(&format!("one of {}", names.join(", ")))
+            })?;
+            out.push(chosen.index());
+            encode_fields(registry, chosen.fields(), fields_value, out)
+                .map_err(|e| eyre!("{}::{}: {e}", type_name(ty), chosen.name()))
+        }
+        TypeDef::Sequence(seq) => {
+            let elem = seq.type_param().id();
+            if let Some(bytes) = byte_string(registry, elem, value)? {
+                Compact(u32::try_from(bytes.len())?).encode_to(out);
+                out.extend_from_slice(&bytes);
+                return Ok(());
+            }
+            let items = value.as_array().ok_or_else(|| err("an array"))?;
+            Compact(u32::try_from(items.len())?).encode_to(out);
+            for item in items {
+                encode_json(registry, elem, item, out)?;
+            }
+            Ok(())
+        }
+        TypeDef::Array(array) => {
+            let elem = array.type_param().id();
+            let len = array.len() as usize;
+            if let Some(bytes) = byte_string(registry, elem, value)? {
+                if bytes.len() != len {
+                    return Err(err(&format!("{len} bytes")));
+                }
+                out.extend_from_slice(&bytes);
+                return Ok(());
+            }
+            let items = value.as_array().ok_or_else(|| err("an array"))?;
+            if items.len() != len {
+                return Err(err(&format!("an array of {len} elements")));
+            }
+            for item in items {
+                encode_json(registry, elem, item, out)?;
+            }
+            Ok(())
+        }
+        TypeDef::Tuple(tuple) => {
+            let fields = tuple.fields();
+            if fields.is_empty() && value.is_null() {
+                return Ok(());
+            }
+            let items = value.as_array().ok_or_else(|| err("an array"))?;
+            if items.len() != fields.len() {
+                return Err(err(&format!("an array of {} elements", fields.len())));
+            }
+            for (field, item) in fields.iter().zip(items) {
+                encode_json(registry, field.id(), item, out)?;
+            }
+            Ok(())
+        }
+        TypeDef::Primitive(primitive) => {
+            encode_primitive(primitive, value, out).map_err(|e| eyre!("`{}`: {e}", type_name(ty)))
+        }
+        TypeDef::Compact(compact) => {
+            let inner = resolve(registry, compact.type_param().id())?;
+            let n = match compact_inner_primitive(registry, inner)? {
+                TypeDefPrimitive::U8
+                | TypeDefPrimitive::U16
+                | TypeDefPrimitive::U32
+                | TypeDefPrimitive::U64
+                | TypeDefPrimitive::U128 => json_u128(value)?,
+                _ => return Err(err("a compact unsigned integer")),
+            };
+            Compact(n).encode_to(out);
+            Ok(())
+        }
+        TypeDef::BitSequence(_) => Err(eyre!(
+            "bit sequences (`{}`) are not supported; use a raw hex override",
+            type_name(ty)
+        )),
+    }
+}
+
+fn encode_fields(
+    registry: &PortableRegistry,
+    fields: &[Field<PortableForm>],
+    value: &JsonValue,
+    out: &mut Vec<u8>,
+) -> Result<()> {
+    if fields.is_empty() {
+        return match value {
+            JsonValue::Null => Ok(()),
+            JsonValue::Array(a) if a.is_empty() => Ok(()),
+            JsonValue::Object(o) if o.is_empty() => Ok(()),
+            _ => Err(eyre!("expected no fields, got {value}")),
+        };
+    }
+    if fields.iter().all(|f| f.name().is_some()) {
+        let map = value
+            .as_object()
+            .ok_or_else(|| eyre!("expected an object, got {value}"))?;
+        if let Some(unknown) = map
+            .keys()
+            .find(|k| !fields.iter().any(|f| f.name() == Some(k)))
+        {
+            return Err(eyre!("unknown field `{unknown}`"));
+        }
+        for field in fields {
+            let name = field.name().expect("all fields are named");
+            let field_value = map
+                .get(name)
+                .ok_or_else(|| eyre!("missing field `{name}`"))?;
+            encode_json(registry, field.ty().id(), field_value, out)
+                .map_err(|e| eyre!("field `{name}`: {e}"))?;
+        }
+        return Ok(());
+    }
+    if let [field] = fields {
+        return encode_json(registry, field.ty().id(), value, out);
+    }
+    let items = value.as_array().ok_or_else(|| {
+        eyre!(
+            "expected an array of {} elements, got {value}",
+            fields.len()
+        )
+    })?;
+    if items.len() != fields.len() {
+        return Err(eyre!(
+            "expected an array of {} elements, got {value}",
+            fields.len()
+        ));
+    }
+    for (field, item) in fields.iter().zip(items) {
+        encode_json(registry, field.ty().id(), item, out)?;
+    }
+    Ok(())
+}
+
+/// The bytes of a string value given for a sequence or array of `u8`:
+/// `0x` hex, an SS58 address (32-byte arrays), or else UTF-8 text.
+fn byte_string(
+    registry: &PortableRegistry,
+    elem: u32,
+    value: &JsonValue,
+) -> Result<Option<Vec<u8>>> {
+    let Some(s) = value.as_str() else {
+        return Ok(None);
+    };
+    let ty = resolve(registry, elem)?;
+    if !matches!(ty.type_def(), TypeDef::Primitive(TypeDefPrimitive::U8)) {
+        return Ok(None);
+    }
+    if let Some(digits) = s.strip_prefix("0x") {
+        return hex::decode(digits)
+            .map(Some)
+            .map_err(|e| eyre!("invalid hex `{s}`: {e}"));
+    }
+    if let Ok(account) = AccountId32::from_ss58check(s) {
+        return Ok(Some(AsRef::<[u8]>::as_ref(&account).to_vec()));
+    }
+    Ok(Some(s.as_bytes().to_vec()))
+}
+
+fn encode_primitive(
+    primitive: &TypeDefPrimitive,
+    value: &JsonValue,
+    out: &mut Vec<u8>,
+) -> Result<()> {
+    macro_rules! int {
+        ($t:ty, $parse:ident) => {{
+            let n = $parse(value)?;
+            <$t>::try_from(n)
+                .map_err(|_| eyre!("{n} is out of range for {}", stringify!($t)))?
+                .encode_to(out);
+        }};
+    }
+    match primitive {
+        TypeDefPrimitive::Bool => value
+            .as_bool()
+            .ok_or_else(|| eyre!("expected a bool, got {value}"))?
+            .encode_to(out),
+        TypeDefPrimitive::Str => value
+            .as_str()
+            .ok_or_else(|| eyre!("expected a string, got {value}"))?
+            .encode_to(out),
+        TypeDefPrimitive::Char => {
+            let s = value.as_str().unwrap_or_default();
+            let mut chars = s.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => u32::from(c).encode_to(out),
+                _ => return Err(eyre!("expected a single character, got {value}")),
+            }
+        }
+        TypeDefPrimitive::U8 => int!(u8, json_u128),
+        TypeDefPrimitive::U16 => int!(u16, json_u128),
+        TypeDefPrimitive::U32 => int!(u32, json_u128),
+        TypeDefPrimitive::U64 => int!(u64, json_u128),
+        TypeDefPrimitive::U128 => int!(u128, json_u128),
+        TypeDefPrimitive::I8 => int!(i8, json_i128),
+        TypeDefPrimitive::I16 => int!(i16, json_i128),
+        TypeDefPrimitive::I32 => int!(i32, json_i128),
+        TypeDefPrimitive::I64 => int!(i64, json_i128),
+        TypeDefPrimitive::I128 => int!(i128, json_i128),
+        TypeDefPrimitive::U256 | TypeDefPrimitive::I256 => {
+            return Err(eyre!(
+                "256-bit integers are not supported; use a raw hex override"
+            ))
+        }
+    }
+    Ok(())
+}
+
+/// Decode a SCALE value of the registry type `type_id` into the JSON shape
+/// [`encode_json`] accepts, so it can be edited and encoded back: byte
+/// sequences and arrays become `0x` hex, integers past `u64` decimal strings,
+/// and `Some(x)` just `x`.
+pub fn decode_json(
+    registry: &PortableRegistry,
+    type_id: u32,
+    input: &mut &[u8],
+) -> Result<JsonValue> {
+    let ty = resolve(registry, type_id)?;
+    let is_u8 = |id: u32| -> Result<bool> {
+        Ok(matches!(
+            resolve(registry, id)?.type_def(),
+            TypeDef::Primitive(TypeDefPrimitive::U8)
+        ))
+    };
+    let take = |input: &mut &[u8], len: usize| -> Result<Vec<u8>> {
+        if input.len() < len {
+            return Err(eyre!("`{}`: unexpected end of input", type_name(ty)));
+        }
+        let (bytes, rest) = input.split_at(len);
+        *input = rest;
+        Ok(bytes.to_vec())
+    };
+
+    Ok(match ty.type_def() {
+        TypeDef::Composite(composite) => decode_fields(registry, composite.fields(), input)?,
+        TypeDef::Variant(variant) => {
+            let index = u8::decode(input)?;
+            let chosen = variant
+                .variants()
+                .iter()
+                .find(|v| v.index() == index)
+                .ok_or_else(|| eyre!("`{}` has no variant {index}", type_name(ty)))?;
+            let fields = decode_fields(registry, chosen.fields(), input)?;
+            match chosen.name().as_str() {
+                "None" if fields.is_null() => JsonValue::Null,
+                "Some" => fields,
+                name if fields.is_null() => JsonValue::String(name.to_owned()),
+                name => {
+                    let mut map = serde_json::Map::new();
+                    map.insert(name.to_owned(), fields);
+                    JsonValue::Object(map)
+                }
+            }
+        }
+        TypeDef::Sequence(seq) => {
+            let len = Compact::<u32>::decode(input)?.0 as usize;
+            let elem = seq.type_param().id();
+            if is_u8(elem)? {
+                JsonValue::String(take(input, len)?.to_hex())
+            } else {
+                (0..len)
+                    .map(|_| decode_json(registry, elem, input))
+                    .collect::<Result<_>>()?
+            }
+        }
+        TypeDef::Array(array) => {
+            let elem = array.type_param().id();
+            if is_u8(elem)? {
+                JsonValue::String(take(input, array.len() as usize)?.to_hex())
+            } else {
+                (0..array.len())
+                    .map(|_| decode_json(registry, elem, input))
+                    .collect::<Result<_>>()?
+            }
+        }
+        TypeDef::Tuple(tuple) if tuple.fields().is_empty() => JsonValue::Null,
+        TypeDef::Tuple(tuple) => tuple
+            .fields()
+            .iter()
+            .map(|f| decode_json(registry, f.id(), input))
+            .collect::<Result<_>>()?,
+        TypeDef::Primitive(primitive) => {
+            decode_primitive(primitive, input).map_err(|e| eyre!("`{}`: {e}", type_name(ty)))?
+        }
+        TypeDef::Compact(_) => {
+            let n = Compact::<u128>::decode(input)?.0;
+            u64::try_from(n).map_or_else(|_| JsonValue::String(n.to_string()), JsonValue::from)
+        }
+        TypeDef::BitSequence(_) => {
+            return Err(eyre!(
+                "bit sequences (`{}`) are not supported",
+                type_name(ty)
+            ))
+        }
+    })
+}
+
+fn decode_fields(
+    registry: &PortableRegistry,
+    fields: &[Field<PortableForm>],
+    input: &mut &[u8],
+) -> Result<JsonValue> {
+    if fields.is_empty() {
+        Ok(JsonValue::Null)
+    } else if fields.iter().all(|f| f.name().is_some()) {
+        fields
+            .iter()
+            .map(|f| {
+                let name = f.name().expect("all fields are named").clone();
+                let value = decode_json(registry, f.ty().id(), input)
+                    .map_err(|e| eyre!("field `{name}`: {e}"))?;
+                Ok((name, value))
+            })
+            .collect::<Result<serde_json::Map<_, _>>>()
+            .map(JsonValue::Object)
+    } else if let [field] = fields {
+        decode_json(registry, field.ty().id(), input)
+    } else {
+        fields
+            .iter()
+            .map(|f| decode_json(registry, f.ty().id(), input))
+            .collect()
+    }
+}
+
+fn decode_primitive(primitive: &TypeDefPrimitive, input: &mut &[u8]) -> Result<JsonValue> {
+    macro_rules! int {
+        ($t:ty) => {
+            JsonValue::from(<$t>::decode(input)?)
+        };
+    }
+    Ok(match primitive {
+        TypeDefPrimitive::Bool => JsonValue::Bool(bool::decode(input)?),
+        TypeDefPrimitive::Str => JsonValue::String(String::decode(input)?),
+        TypeDefPrimitive::Char => {
+            let c = char::from_u32(u32::decode(input)?).ok_or_else(|| eyre!("invalid char"))?;
+            JsonValue::String(c.to_string())
+        }
+        TypeDefPrimitive::U8 => int!(u8),
+        TypeDefPrimitive::U16 => int!(u16),
+        TypeDefPrimitive::U32 => int!(u32),
+        TypeDefPrimitive::U64 => int!(u64),
+        TypeDefPrimitive::U128 => JsonValue::String(u128::decode(input)?.to_string()),
+        TypeDefPrimitive::I8 => int!(i8),
+        TypeDefPrimitive::I16 => int!(i16),
+        TypeDefPrimitive::I32 => int!(i32),
+        TypeDefPrimitive::I64 => int!(i64),
+        TypeDefPrimitive::I128 => JsonValue::String(i128::decode(input)?.to_string()),
+        TypeDefPrimitive::U256 | TypeDefPrimitive::I256 => {
+            return Err(eyre!("256-bit integers are not supported"))
+        }
+    })
+}
+
+/// Decode a whole storage value, rejecting trailing bytes.
+pub fn decode_value(registry: &PortableRegistry, type_id: u32, bytes: &[u8]) -> Result<JsonValue> {
+    let mut input = bytes;
+    let value = decode_json(registry, type_id, &mut input)?;
+    if !input.is_empty() {
+        return Err(eyre!("{} trailing byte(s) after the value", input.len()));
+    }
+    Ok(value)
+}
+
+/// The zero value of the registry type `type_id` as JSON, in the shape
+/// [`encode_json`] accepts: zeros, empty sequences, `null` for `Option`s and
+/// the first variant of other enums. Used as a template whose fields are
+/// then filled in by name, so values survive fields added in newer runtimes.
+pub fn default_json(registry: &PortableRegistry, type_id: u32) -> Result<JsonValue> {
+    let ty = resolve(registry, type_id)?;
+    let fields_default = |fields: &[Field<PortableForm>]| -> Result<JsonValue> {
+        if fields.is_empty() {
+            Ok(JsonValue::Null)
+        } else if fields.iter().all(|f| f.name().is_some()) {
+            fields
+                .iter()
+                .map(|f| {
+                    let name = f.name().expect("all fields are named").clone();
+                    Ok((name, default_json(registry, f.ty().id())?))
+                })
+                .collect::<Result<serde_json::Map<_, _>>>()
+                .map(JsonValue::Object)
+        } else if let [field] = fields {
+            default_json(registry, field.ty().id())
+        } else {
+            fields
+                .iter()
+                .map(|f| default_json(registry, f.ty().id()))
+                .collect::<Result<Vec<_>>>()
+                .map(JsonValue::Array)
+        }
+    };
+
+    Ok(match ty.type_def() {
+        TypeDef::Composite(composite) => fields_default(composite.fields())?,
+        TypeDef::Variant(variant) => {
+            let variants = variant.variants();
+            if variants.iter().any(|v| v.name() == "None") {
+                JsonValue::Null
+            } else {
+                let first = variants
+                    .iter()
+                    .min_by_key(|v| v.index())
+                    .ok_or_else(|| eyre!("`{}` has no variants", type_name(ty)))?;
+                if first.fields().is_empty() {
+                    JsonValue::String(first.name().clone())
+                } else {
+                    let mut map = serde_json::Map::new();
+                    map.insert(first.name().clone(), fields_default(first.fields())?);
+                    JsonValue::Object(map)
+                }
+            }
+        }
+        TypeDef::Sequence(_) => JsonValue::Array(Vec::new()),
+        TypeDef::Array(array) => {
+            let elem = resolve(registry, array.type_param().id())?;
+            if matches!(elem.type_def(), TypeDef::Primitive(TypeDefPrimitive::U8)) {
+                JsonValue::String(format!("0x{}", "00".repeat(array.len() as usize)))
+            } else {
+                let item = default_json(registry, array.type_param().id())?;
+                JsonValue::Array(vec![item; array.len() as usize])
+            }
+        }
+        TypeDef::Tuple(tuple) => JsonValue::Array(
+            tuple
+                .fields()
+                .iter()
+                .map(|f| default_json(registry, f.id()))
+                .collect::<Result<_>>()?,
+        ),
+        TypeDef::Primitive(TypeDefPrimitive::Bool) => JsonValue::Bool(false),
+        TypeDef::Primitive(TypeDefPrimitive::Str) => JsonValue::String(String::new()),
+        TypeDef::Primitive(TypeDefPrimitive::Char) => JsonValue::String("\0".to_owned()),
+        TypeDef::Primitive(_) | TypeDef::Compact(_) => JsonValue::from(0),
+        TypeDef::BitSequence(_) => {
+            return Err(eyre!(
+                "bit sequences (`{}`) are not supported",
+                type_name(ty)
+            ))
+        }
+    })
+}
+
+/// Set `field` on a JSON object built by [`default_json`], if the type has it.
+pub fn set_field(value: &mut JsonValue, field: &str, to: JsonValue) {
+    if let Some(slot) = value.as_object_mut().and_then(|o| o.get_mut(field)) {
+        *slot = to;
+    }
+}
+
+/// The element type of a sequence, looking through newtype wrappers such as
+/// `BoundedVec`.
+pub fn element_type(registry: &PortableRegistry, type_id: u32) -> Result<Option<u32>> {
+    match resolve(registry, type_id)?.type_def() {
+        TypeDef::Sequence(seq) => Ok(Some(seq.type_param().id())),
+        TypeDef::Composite(composite) => match composite.fields() {
+            [field] => element_type(registry, field.ty().id()),
+            _ => Ok(None),
+        },
+        _ => Ok(None),
+    }
+}
+
+/// Whether the registry type `type_id` is, or (transitively) contains, a
+/// type satisfying `pred`.
+pub fn contains_type(
+    registry: &PortableRegistry,
+    type_id: u32,
+    pred: &dyn Fn(&Type<PortableForm>) -> bool,
+) -> bool {
+    fn walk(
+        registry: &PortableRegistry,
+        type_id: u32,
+        pred: &dyn Fn(&Type<PortableForm>) -> bool,
+        seen: &mut Vec<u32>,
+    ) -> bool {
+        if seen.contains(&type_id) {
+            return false;
+        }
+        seen.push(type_id);
+        let Ok(ty) = resolve(registry, type_id) else {
+            return false;
+        };
+        if pred(ty) {
+            return true;
+        }
+        let mut children = Vec::new();
+        match ty.type_def() {
+            TypeDef::Composite(c) => children.extend(c.fields().iter().map(|f| f.ty().id())),
+            TypeDef::Variant(v) => children.extend(
+                v.variants()
+                    .iter()
+                    .flat_map(|v| v.fields())
+                    .map(|f| f.ty().id()),
+            ),
+            TypeDef::Sequence(s) => children.push(s.type_param().id()),
+            TypeDef::Array(a) => children.push(a.type_param().id()),
+            TypeDef::Tuple(t) => children.extend(t.fields().iter().map(|f| f.id())),
+            TypeDef::Compact(c) => children.push(c.type_param().id()),
+            TypeDef::Primitive(_) | TypeDef::BitSequence(_) => {}
+        }
+        children
+            .into_iter()
+            .any(|child| walk(registry, child, pred, seen))
+    }
+    walk(registry, type_id, pred, &mut Vec::new())
+}
+
+/// An unsigned integer from a JSON number or decimal string.
+pub fn json_u128(value: &JsonValue) -> Result<u128> {
+    match value {
+        JsonValue::Number(n) => n.as_u64().map(u128::from),
+        JsonValue::String(s) => s.parse().ok(),
+        _ => None,
+    }
+    .ok_or_else(|| eyre!("expected an unsigned integer, got {value}"))
+}
+
+fn json_i128(value: &JsonValue) -> Result<i128> {
+    match value {
+        JsonValue::Number(n) => n.as_i64().map(i128::from),
+        JsonValue::String(s) => s.parse().ok(),
+        _ => None,
+    }
+    .ok_or_else(|| eyre!("expected an integer, got {value}"))
+}
+
+/// The primitive behind a `Compact<T>`, looking through single-field
+/// wrappers such as `Perbill(u32)`.
+fn compact_inner_primitive<'a>(
+    registry: &'a PortableRegistry,
+    ty: &'a Type<PortableForm>,
+) -> Result<&'a TypeDefPrimitive> {
+    match ty.type_def() {
+        TypeDef::Primitive(p) => Ok(p),
+        TypeDef::Composite(c) if c.fields().len() == 1 => {
+            compact_inner_primitive(registry, resolve(registry, c.fields()[0].ty().id())?)
+        }
+        _ => Err(eyre!("unsupported compact type `{}`", type_name(ty))),
+    }
+}
+
+pub fn resolve(registry: &PortableRegistry, type_id: u32) -> Result<&Type<PortableForm>> {
+    registry
+        .resolve(type_id)
+        .ok_or_else(|| eyre!("type {type_id} is missing from the metadata registry"))
+}
+
+/// A readable name for error messages: the type's path, or its shape.
+pub fn type_name(ty: &Type<PortableForm>) -> String {
+    let segments = ty.path().segments();
+    if !segments.is_empty() {
+        return segments.join("::");
+    }
+    match ty.type_def() {
+        TypeDef::Composite(_) => "struct".to_owned(),
+        TypeDef::Variant(_) => "enum".to_owned(),
+        TypeDef::Sequence(_) => "Vec<_>".to_owned(),
+        TypeDef::Array(a) => format!("[_; {}]", a.len()),
+        TypeDef::Tuple(_) => "tuple".to_owned(),
+        TypeDef::Primitive(p) => format!("{p:?}").to_lowercase(),
+        TypeDef::Compact(_) => "Compact<_>".to_owned(),
+        TypeDef::BitSequence(_) => "BitVec".to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use scale_info::{meta_type, Registry, TypeInfo};
+    use serde_json::json;
+
+    use super::*;
+
+    #[derive(TypeInfo)]
+    #[allow(dead_code)]
+    struct AccountData {
+        free: u128,
+        reserved: u128,
+        flags: (u8, bool),
+    }
+
+    #[derive(TypeInfo)]
+    #[allow(dead_code)]
+    enum Payee {
+        Staked,
+        Account([u8; 32]),
+    }
+
+    fn registry_with<T: TypeInfo + 'static>() -> (PortableRegistry, u32) {
+        let mut registry = Registry::new();
+        let id = registry.register_type(&meta_type::<T>()).id();
+        (registry.into(), id)
+    }
+
+    fn encode<T: TypeInfo + 'static>(value: JsonValue) -> Result<Vec<u8>> {
+        let (registry, id) = registry_with::<T>();
+        let mut out = Vec::new();
+        encode_json(&registry, id, &value, &mut out)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn encodes_structs_by_field_name() {
+        let out = encode::<AccountData>(json!({
+            "free": "1000000000000000000000",
+            "reserved": 5,
+            "flags": [1, true],
+        }))
+        .unwrap();
+        let mut expected = 1_000_000_000_000_000_000_000u128.encode();
+        expected.extend(5u128.encode());
+        expected.extend([1, 1]);
+        assert_eq!(out, expected);
+
+        let err = encode::<AccountData>(json!({"free": 1, "reserved": 0})).unwrap_err();
+        assert!(err.to_string().contains("missing field `flags`"), "{err}");
+    }
+
+    #[test]
+    fn encodes_enums_options_and_bytes() {
+        assert_eq!(encode::<Payee>(json!("Staked")).unwrap(), vec![0]);
+        let alice = "5GrwvaEF5zXb26Fz9rcQpDWS57CtERHpNehXCPcNoHGKutQY";
+        let out = encode::<Payee>(json!({ "Account": alice })).unwrap();
+        assert_eq!(out[0], 1);
+        assert_eq!(
+            out[1..].to_vec(),
+            AsRef::<[u8]>::as_ref(&AccountId32::from_ss58check(alice).unwrap()).to_vec()
+        );
+
+        assert_eq!(encode::<Option<u32>>(json!(null)).unwrap(), vec![0]);
+        assert_eq!(
+            encode::<Option<u32>>(json!(7)).unwrap(),
+            vec![1, 7, 0, 0, 0]
+        );
+        assert_eq!(
+            encode::<Vec<u8>>(json!("0xbeef")).unwrap(),
+            vec![8, 0xbe, 0xef]
+        );
+        assert!(encode::<u8>(json!(256)).is_err());
+    }
+
+    #[test]
+    fn decoding_round_trips_through_encoding() {
+        let (registry, id) = registry_with::<Vec<(u32, Option<Payee>, AccountData)>>();
+        let value = json!([
+            [7, null, {"free": "1000000000000000000000", "reserved": "5", "flags": [1, true]}],
+            [8, {"Account": format!("0x{}", "11".repeat(32))}, {"free": "0", "reserved": "0", "flags": [0, false]}],
+            [9, "Staked", {"free": "1", "reserved": "2", "flags": [3, false]}],
+        ]);
+        let mut encoded = Vec::new();
+        encode_json(&registry, id, &value, &mut encoded).unwrap();
+        assert_eq!(decode_value(&registry, id, &encoded).unwrap(), value);
+        assert!(decode_value(&registry, id, &[encoded, vec![0]].concat()).is_err());
+    }
+
+    #[test]
+    fn default_values_encode_as_zero() {
+        let (registry, id) = registry_with::<AccountData>();
+        let mut value = default_json(&registry, id).unwrap();
+        assert_eq!(
+            value,
+            json!({"free": 0, "reserved": 0, "flags": [0, false]})
+        );
+        set_field(&mut value, "free", json!("12"));
+        set_field(&mut value, "missing", json!(1));
+        let mut out = Vec::new();
+        encode_json(&registry, id, &value, &mut out).unwrap();
+        assert_eq!(out[..16], 12u128.to_le_bytes());
+
+        let (registry, id) = registry_with::<Payee>();
+        assert_eq!(default_json(&registry, id).unwrap(), json!("Staked"));
+        let (registry, id) = registry_with::<Option<[u8; 2]>>();
+        assert_eq!(default_json(&registry, id).unwrap(), JsonValue::Null);
+    }
+}
@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use codec::Decode;
+use color_eyre::{eyre::eyre, Result};
+use frame_metadata::RuntimeMetadataV14;
+use serde_json::{json, Value as JsonValue};
+
+use crate::impersonate::Transplant;
+use crate::metadata::{self, put_typed};
+use crate::scale::{decode_value, json_u128, set_field};
+use crate::unbonding::{rebase_value, EraRebase};
+use crate::validators::rebuild_voter_list;
+use crate::{json_hex_bytes, read_mapped_entries, storage_prefix, ToHex};
+
+/// The account at the end of a storage key of a map keyed by one account
+/// with a concat hasher (`Blake2_128Concat`, `Twox64Concat`).
+pub fn trailing_account(key: &str) -> Option<[u8; 32]> {
+    let bytes = hex::decode(key.strip_prefix("0x")?).ok()?;
+    bytes.get(bytes.len().checked_sub(32)?..)?.try_into().ok()
+}
+
+fn stash_of(ledger: &JsonValue) -> Option<[u8; 32]> {
+    let digits = ledger.get("stash")?.as_str()?.strip_prefix("0x")?;
+    hex::decode(digits).ok()?.try_into().ok()
+}
+
+/// Carry every staker of the original chain over to the fork: their
+/// `Staking.Bonded`, `Ledger` (with unbonding eras rebased) and `Payee`, and
+/// their `Nominators` entries re-pointed at the fork's validators (read from
+/// `Session.Validators` in `overrides`). `VoterList` is rebuilt for the
+/// fork's validators and the preserved nominators. Only entries `keep`
+/// accepts are carried over, with impersonated accounts moved by
+/// `transplant` as in the rest of the state.
+pub fn preserve_stakers(
+    meta: &RuntimeMetadataV14,
+    storage_path: &Path,
+    keep: impl Fn(&str) -> bool,
+    transplant: Option<&Transplant>,
+    rebase: EraRebase,
+    total_issuance: u128,
+    overrides: &mut serde_json::Map<String, JsonValue>,
+) -> Result<()> {
+    let validators: Vec<[u8; 32]> = overrides
+        .get(&storage_prefix("Session", "Validators"))
+        .and_then(json_hex_bytes)
+        .and_then(|bytes| Vec::<[u8; 32]>::decode(&mut &bytes[..]).ok())
+        .ok_or_else(|| eyre!("the fork has no Session.Validators to nominate"))?;
+    let max_nominations = metadata::constant(meta, "Staking", "MaxNominations")
+        .and_then(|mut bytes| u32::decode(&mut bytes).ok())
+        .map_or(validators.len(), |max| max as usize);
+    let targets: Vec<JsonValue> = validators
+        .iter()
+        .take(max_nominations)
+        .map(|v| v.to_hex().into())
+        .collect();
+
+    let bonded_prefix = storage_prefix("Staking", "Bonded");
+    let ledger_prefix = storage_prefix("Staking", "Ledger");
+    let payee_prefix = storage_prefix("Staking", "Payee");
+    let nominators_prefix = storage_prefix("Staking", "Nominators");
+    let prefixes = [
+        &bonded_prefix,
+        &ledger_prefix,
+        &payee_prefix,
+        &nominators_prefix,
+    ];
+    let entries = read_mapped_entries(storage_path, |key, value| {
+        if !prefixes.iter().any(|p| key.starts_with(p.as_str())) || !keep(key) {
+            return None;
+        }
+        Some(
+            transplant
+                .and_then(|t| t.rewrite(key, value))
+                .unwrap_or_else(|| (key.to_owned(), value.to_owned())),
+        )
+    })?;
+
+    let mut active: HashMap<[u8; 32], u128> = HashMap::new();
+    let mut nominators = Vec::new();
+    let mut ledgers = 0usize;
+    for (key, value) in entries {
+        // The fork's validators keep their injected staking state.
+        if trailing_account(&key).is_some_and(|a| validators.contains(&a)) {
+            continue;
+        }
+        let rewritten = if key.starts_with(&ledger_prefix) {
+            ledgers += 1;
+            rebase_value(meta, "Staking", "Ledger", &value, |ledger| {
+                rebase.ledger(ledger)?;
+                // Reward claims refer to the original chain's eras.
+                set_field(ledger, "legacy_claimed_rewards", json!([]));
+                set_field(ledger, "claimed_rewards", json!([]));
+                if let Some(stash) = stash_of(ledger) {
+                    active.insert(stash, json_u128(&ledger["active"])?);
+                }
+                Ok(())
+            })?
+        } else if key.starts_with(&nominators_prefix) {
+            nominators.extend(trailing_account(&key));
+            rebase_value(meta, "Staking", "Nominators", &value, |nominations| {
+                set_field(nominations, "targets", JsonValue::Array(targets.clone()));
+                set_field(nominations, "submitted_in", json!(rebase.fork_era));
+                Ok(())
+            })?
+        } else {
+            None
+        };
+        overrides.insert(key, rewritten.unwrap_or(value).into());
+    }
+
+    if let Some(counter) = metadata::find_item(meta, "Staking", "CounterForNominators") {
+        put_typed(meta, overrides, &counter, &[], &json!(nominators.len()))?;
+    }
+
+    // Validators first, so they head their bags.
+    let mut voters: Vec<([u8; 32], u128)> = Vec::new();
+    let ledger_item = metadata::storage_item(meta, "Staking", "Ledger")?;
+    for validator in &validators {
+        let stake = overrides
+            .get(&ledger_item.key(&meta.types, &[validator.to_hex().into()])?)
+            .and_then(json_hex_bytes)
+            .and_then(|bytes| decode_value(&meta.types, ledger_item.value_type(), &bytes).ok())
+            .and_then(|ledger| json_u128(&ledger["active"]).ok())
+            .unwrap_or(0);
+        voters.push((*validator, stake));
+    }
+    voters.extend(
+        nominators
+            .iter()
+            .map(|n| (*n, active.get(n).copied().unwrap_or(0))),
+    );
+    rebuild_voter_list(meta, &voters, total_issuance, overrides)?;
+
+    println!(
+        "Preserved {ledgers} staking ledger(s); {} nominator(s) now nominate the fork's {} validator(s)",
+        nominators.len(),
+        validators.len()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accounts::parse_account;
+    use crate::staking_ledger_key;
+
+    #[test]
+    fn accounts_are_read_from_concat_keys() {
+        let alice = parse_account("//Alice").unwrap();
+        assert_eq!(trailing_account(&staking_ledger_key(&alice)), Some(alice));
+        assert_eq!(trailing_account("0x1234"), None);
+        let ledger = json!({"stash": alice.to_hex(), "total": 1});
+        assert_eq!(stash_of(&ledger), Some(alice));
+    }
+}
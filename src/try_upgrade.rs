@@ -0,0 +1,98 @@
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+use color_eyre::{eyre::eyre, Result};
+use tokio::process::Command;
+
+use crate::cli::TryUpgradeArgs;
+
+/// The helper that runs the migrations: a separate binary (`try-upgrade/`),
+/// as the wasm executor can't be built alongside this tool's `subxt`.
+const HELPER: &str = "creditcoin-fork-try-upgrade";
+
+/// The helper's path: `--helper`, else next to this binary if it is there,
+/// else looked up on `PATH`.
+fn helper_path(args: &TryUpgradeArgs) -> PathBuf {
+    args.helper.clone().unwrap_or_else(|| {
+        std::env::current_exe()
+            .ok()
+            .map(|exe| exe.with_file_name(HELPER))
+            .filter(|path| path.exists())
+            .unwrap_or_else(|| PathBuf::from(HELPER))
+    })
+}
+
+fn helper_args(args: &TryUpgradeArgs) -> Vec<OsString> {
+    let mut out = vec![
+        args.storage.clone().into(),
+        "--runtime".into(),
+        args.runtime.clone().into(),
+    ];
+    if args.try_state {
+        out.push("--try-state".into());
+    }
+    if let Some(pages) = args.heap_pages {
+        out.extend(["--heap-pages".into(), pages.to_string().into()]);
+    }
+    out
+}
+
+/// Run the `--runtime` blob's migrations against the cached state with the
+/// helper, failing if they (or their checks) do.
+pub async fn run(args: &TryUpgradeArgs) -> Result<()> {
+    if !args.storage.exists() {
+        return Err(eyre!(
+            "{} does not exist; build the fork (with `--storage` or the default cache) first",
+            args.storage.display()
+        ));
+    }
+    let helper = helper_path(args);
+    let status = Command::new(&helper)
+        .args(helper_args(args))
+        .status()
+        .await
+        .map_err(|e| {
+            eyre!(
+                "failed to run {}: {e} (install it with `cargo install --path try-upgrade`)",
+                helper.display()
+            )
+        })?;
+    if !status.success() {
+        return Err(eyre!("the upgrade failed ({status})"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_helper_gets_the_subcommands_options() {
+        let mut args = TryUpgradeArgs {
+            storage: "fork.json.storage.json".into(),
+            runtime: "runtime.wasm".into(),
+            try_state: false,
+            heap_pages: None,
+            helper: None,
+        };
+        assert_eq!(
+            helper_args(&args),
+            ["fork.json.storage.json", "--runtime", "runtime.wasm"].map(OsString::from)
+        );
+        args.try_state = true;
+        args.heap_pages = Some(64);
+        assert_eq!(
+            helper_args(&args),
+            [
+                "fork.json.storage.json",
+                "--runtime",
+                "runtime.wasm",
+                "--try-state",
+                "--heap-pages",
+                "64"
+            ]
+            .map(OsString::from)
+        );
+    }
+}
@@ -0,0 +1,174 @@
+use color_eyre::{eyre::eyre, Result};
+use frame_metadata::RuntimeMetadataV14;
+use serde_json::Value as JsonValue;
+
+use crate::metadata;
+use crate::scale::{decode_value, encode_json, json_u128};
+use crate::ToHex;
+
+/// Maps the original chain's era numbers onto the fork's, keeping each era's
+/// distance from the current era: an unbond withdrawable in 3 eras on the
+/// original chain is withdrawable in 3 eras on the fork.
+#[derive(Clone, Copy, Debug)]
+pub struct EraRebase {
+    pub original_era: u32,
+    pub fork_era: u32,
+}
+
+impl EraRebase {
+    pub fn era(&self, era: u32) -> u32 {
+        if era >= self.original_era {
+            self.fork_era.saturating_add(era - self.original_era)
+        } else {
+            self.fork_era.saturating_sub(self.original_era - era)
+        }
+    }
+
+    fn era_json(&self, era: &JsonValue) -> Result<JsonValue> {
+        let era = u32::try_from(json_u128(era)?)?;
+        Ok(self.era(era).into())
+    }
+
+    /// Shift a `StakingLedger`'s `unlocking` chunks, merging chunks that
+    /// land on the same era.
+    pub fn ledger(&self, ledger: &mut JsonValue) -> Result<()> {
+        let Some(chunks) = ledger
+            .get_mut("unlocking")
+            .and_then(JsonValue::as_array_mut)
+        else {
+            return Ok(());
+        };
+        let mut rebased: Vec<JsonValue> = Vec::with_capacity(chunks.len());
+        for chunk in chunks.iter() {
+            let era = self.era_json(&chunk["era"])?;
+            match rebased.iter_mut().find(|c| c["era"] == era) {
+                Some(existing) => add_json(&mut existing["value"], &chunk["value"])?,
+                None => {
+                    let mut chunk = chunk.clone();
+                    chunk["era"] = era;
+                    rebased.push(chunk);
+                }
+            }
+        }
+        *chunks = rebased;
+        Ok(())
+    }
+
+    /// Shift the era keys of the `[[era, value], ...]` map under `field`
+    /// (`PoolMember::unbonding_eras`, `SubPools::with_era`), summing values
+    /// that land on the same era.
+    pub fn era_map(&self, value: &mut JsonValue, field: &str) -> Result<()> {
+        let Some(entries) = value.get_mut(field).and_then(JsonValue::as_array_mut) else {
+            return Ok(());
+        };
+        let mut rebased: Vec<(u32, JsonValue)> = Vec::with_capacity(entries.len());
+        for entry in entries.iter() {
+            let era = self.era(u32::try_from(json_u128(&entry[0])?)?);
+            match rebased.iter_mut().find(|(e, _)| *e == era) {
+                Some((_, existing)) => add_json(existing, &entry[1])?,
+                None => rebased.push((era, entry[1].clone())),
+            }
+        }
+        // A BTreeMap encodes in key order.
+        rebased.sort_by_key(|(era, _)| *era);
+        *entries = rebased
+            .into_iter()
+            .map(|(era, v)| JsonValue::Array(vec![era.into(), v]))
+            .collect();
+        Ok(())
+    }
+}
+
+/// Add balances (or structs of balances, such as `UnbondPool`) field-wise.
+fn add_json(into: &mut JsonValue, other: &JsonValue) -> Result<()> {
+    if let (JsonValue::Object(into), JsonValue::Object(other)) = (&mut *into, other) {
+        for (field, value) in other {
+            let slot = into
+                .get_mut(field)
+                .ok_or_else(|| eyre!("missing field `{field}`"))?;
+            add_json(slot, value)?;
+        }
+        return Ok(());
+    }
+    let sum = json_u128(into)?
+        .checked_add(json_u128(other)?)
+        .ok_or_else(|| eyre!("balance overflow"))?;
+    *into = sum.to_string().into();
+    Ok(())
+}
+
+/// Decode the value of `pallet.item`, apply `rebase` and encode it again.
+/// `None` if the runtime has no such item.
+pub fn rebase_value(
+    meta: &RuntimeMetadataV14,
+    pallet: &str,
+    item: &str,
+    value: &str,
+    rebase: impl FnOnce(&mut JsonValue) -> Result<()>,
+) -> Result<Option<String>> {
+    let Some(found) = metadata::find_item(meta, pallet, item) else {
+        return Ok(None);
+    };
+    let bytes = hex::decode(value.trim_start_matches("0x"))?;
+    let mut json = decode_value(&meta.types, found.value_type(), &bytes)
+        .map_err(|e| eyre!("{pallet}.{item}: {e}"))?;
+    rebase(&mut json)?;
+    let mut encoded = Vec::new();
+    encode_json(&meta.types, found.value_type(), &json, &mut encoded)
+        .map_err(|e| eyre!("{pallet}.{item}: {e}"))?;
+    Ok(Some(encoded.to_hex()))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    const REBASE: EraRebase = EraRebase {
+        original_era: 1000,
+        fork_era: 2,
+    };
+
+    #[test]
+    fn eras_keep_their_distance_from_the_current_era() {
+        assert_eq!(REBASE.era(1000), 2);
+        assert_eq!(REBASE.era(1028), 30);
+        assert_eq!(REBASE.era(999), 1);
+        assert_eq!(REBASE.era(10), 0);
+    }
+
+    #[test]
+    fn ledger_chunks_are_shifted_and_merged() {
+        let mut ledger = json!({
+            "stash": "0x00",
+            "unlocking": [
+                {"value": 5, "era": 990},
+                {"value": "7", "era": 995},
+                {"value": 1, "era": 1010},
+            ],
+        });
+        REBASE.ledger(&mut ledger).unwrap();
+        assert_eq!(
+            ledger["unlocking"],
+            json!([{"value": "12", "era": 0}, {"value": 1, "era": 12}])
+        );
+    }
+
+    #[test]
+    fn era_maps_are_shifted_merged_and_sorted() {
+        let mut sub_pools = json!({
+            "no_era": {"points": 0, "balance": 0},
+            "with_era": [
+                [1, {"points": 1, "balance": 2}],
+                [1003, {"points": 3, "balance": 4}],
+                [2, {"points": 5, "balance": 6}],
+            ],
+        });
+        REBASE.era_map(&mut sub_pools, "with_era").unwrap();
+        assert_eq!(
+            sub_pools["with_era"],
+            json!([[0, {"points": "6", "balance": "8"}], [5, {"points": 3, "balance": 4}]])
+        );
+    }
+}
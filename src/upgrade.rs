@@ -0,0 +1,279 @@
+use color_eyre::{eyre::eyre, Result};
+use console::style;
+use frame_metadata::RuntimeMetadataV14;
+use serde_json::Value as JsonValue;
+use subxt::dynamic::Value;
+use subxt::ext::sp_core::{sr25519, Pair as _, H256};
+use subxt::tx::PairSigner;
+use tokio::process::Command;
+
+use crate::cli::{Cli, UpgradeTestArgs};
+use crate::node::{report_problems, LocalNode};
+use crate::scale::decode_value;
+use crate::{build_fork, metadata, runtime, storage_prefix, ApiClient, CreditcoinConfig};
+
+async fn runtime_metadata(api: &ApiClient) -> Result<RuntimeMetadataV14> {
+    Ok(api.rpc().metadata().await?.runtime_metadata().clone())
+}
+
+/// Decode `pallet.item` (a plain storage value) at block `at`.
+async fn read_value(
+    api: &ApiClient,
+    meta: &RuntimeMetadataV14,
+    pallet: &str,
+    item: &str,
+    at: H256,
+) -> Result<Option<JsonValue>> {
+    let found = metadata::storage_item(meta, pallet, item)?;
+    let key = hex::decode(&storage_prefix(pallet, item)[2..])?;
+    api.rpc()
+        .storage(&key, Some(at))
+        .await?
+        .map(|data| decode_value(&meta.types, found.value_type(), &data.0))
+        .transpose()
+}
+
+/// Hash of the finalized block `number`, found by walking back from the
+/// finalized head.
+async fn finalized_hash(api: &ApiClient, number: u32) -> Result<H256> {
+    let mut hash = api.rpc().finalized_head().await?;
+    loop {
+        let header = api
+            .rpc()
+            .header(Some(hash))
+            .await?
+            .ok_or_else(|| eyre!("missing header {hash:?}"))?;
+        if header.number <= number {
+            return Ok(hash);
+        }
+        hash = header.parent_hash;
+    }
+}
+
+/// `Pallet.Variant` of an `EventRecord`, its fields and whether it reports a
+/// failure (a failed extrinsic or sudo call).
+fn describe_event(record: &JsonValue) -> Option<(String, JsonValue, bool)> {
+    let (pallet, event) = record.get("event")?.as_object()?.iter().next()?;
+    let (variant, fields) = match event {
+        JsonValue::String(variant) => (variant.as_str(), JsonValue::Null),
+        JsonValue::Object(map) => {
+            let (variant, fields) = map.iter().next()?;
+            (variant.as_str(), fields.clone())
+        }
+        _ => return None,
+    };
+    let failed = match (pallet.as_str(), variant) {
+        ("System", "ExtrinsicFailed") => true,
+        ("Sudo", "Sudid" | "SudoAsDone") => fields
+            .as_object()
+            .and_then(|f| f.values().next())
+            .is_some_and(|result| result.get("Err").is_some()),
+        _ => false,
+    };
+    Some((format!("{pallet}.{variant}"), fields, failed))
+}
+
+/// Print the events of block `at` (only those of block initialization, where
+/// migrations run, with `initialization_only`); `true` if none failed.
+async fn report_events(
+    api: &ApiClient,
+    meta: &RuntimeMetadataV14,
+    at: H256,
+    initialization_only: bool,
+) -> Result<bool> {
+    let events = read_value(api, meta, "System", "Events", at).await?;
+    let mut ok = true;
+    for record in events
+        .iter()
+        .flat_map(|e| e.as_array().into_iter().flatten())
+    {
+        if initialization_only && record["phase"] != "Initialization" {
+            continue;
+        }
+        let Some((name, fields, failed)) = describe_event(record) else {
+            continue;
+        };
+        let line = if fields.is_null() {
+            name
+        } else {
+            format!("{name} {fields}")
+        };
+        if failed {
+            ok = false;
+            println!("  {}", style(line).red());
+        } else {
+            println!("  {line}");
+        }
+    }
+    Ok(ok)
+}
+
+/// Build the fork from `--config` (if given) with the on-chain runtime, boot
+/// it, upgrade its runtime to `--wasm` with `sudo(set_code)` and check the
+/// upgrade went through: the spec version moved and
+/// `System.LastRuntimeUpgrade` records it.
+pub async fn upgrade_test(args: &UpgradeTestArgs) -> Result<()> {
+    let wasm = tokio::fs::read(&args.wasm)
+        .await
+        .map_err(|e| eyre!("{}: {e}", args.wasm.display()))?;
+    let wasm = runtime::prepare_blob(wasm, args.compress_runtime, args.max_code_size)
+        .map_err(|e| eyre!("{}: {e}", args.wasm.display()))?;
+    println!(
+        "Upgrading to {}",
+        runtime::RuntimeVersion::of_blob(&wasm).map_or_else(
+            |e| format!("a runtime of unknown version ({e})"),
+            |version| version.to_string()
+        )
+    );
+
+    if let Some(recipe) = &args.config {
+        let mut cli = Cli::from_recipe(recipe)?;
+        if cli.runtime.is_some() || cli.runtime_at.is_some() {
+            return Err(eyre!(
+                "{} sets the fork's runtime; the upgrade is rehearsed from the on-chain runtime",
+                recipe.display()
+            ));
+        }
+        cli.binary.get_or_insert_with(|| args.node.binary.clone());
+        cli.out.clone_from(&args.spec);
+        build_fork(cli).await?;
+    }
+
+    let signer = PairSigner::<CreditcoinConfig, _>::new(
+        sr25519::Pair::from_string(&args.signer, None)
+            .map_err(|e| eyre!("invalid --signer: {e:?}"))?,
+    );
+
+    let mut node = LocalNode::start(&args.node, &args.spec)?;
+    let outcome = rehearse(args, &mut node, &wasm, &signer).await;
+    let clean = report_problems(&node);
+    let outcome = outcome.and_then(|()| {
+        if clean {
+            Ok(())
+        } else {
+            Err(eyre!("the node logged panics or migration errors"))
+        }
+    });
+    match &outcome {
+        Ok(()) => println!("{}", style("Runtime upgrade succeeded").green()),
+        Err(e) => println!("{} {e}", style("Runtime upgrade failed:").red()),
+    }
+    node.finish(args.keep_running).await?;
+    outcome
+}
+
+async fn rehearse(
+    args: &UpgradeTestArgs,
+    node: &mut LocalNode,
+    wasm: &[u8],
+    signer: &PairSigner<CreditcoinConfig, sr25519::Pair>,
+) -> Result<()> {
+    let api = node.connect().await?;
+    node.wait_for_finalized(&api, 1).await?;
+    let old_meta = runtime_metadata(&api).await?;
+    let old_version = api.rpc().runtime_version(None).await?.spec_version;
+    println!("Running spec version {old_version}; submitting sudo(system.set_code)");
+
+    let set_code = Value::unnamed_variant(
+        "System",
+        [Value::named_variant(
+            "set_code",
+            [("code", Value::from_bytes(wasm))],
+        )],
+    );
+    let payload = subxt::dynamic::tx("Sudo", "sudo", vec![set_code]);
+    let included = api
+        .tx()
+        .sign_and_submit_then_watch_default(&payload, signer)
+        .await?
+        .wait_for_finalized()
+        .await?
+        .block_hash();
+    let included_number = api
+        .rpc()
+        .header(Some(included))
+        .await?
+        .ok_or_else(|| eyre!("missing header {included:?}"))?
+        .number;
+    println!("Included in block #{included_number}:");
+    if !report_events(&api, &old_meta, included, false).await? {
+        return Err(eyre!("sudo(system.set_code) failed"));
+    }
+
+    // Migrations run in the first block executed with the new code.
+    node.wait_for_finalized(&api, included_number + 1).await?;
+    let upgrade_block = finalized_hash(&api, included_number + 1).await?;
+    let new_meta = runtime_metadata(&api).await?;
+    let new_version = api
+        .rpc()
+        .runtime_version(Some(upgrade_block))
+        .await?
+        .spec_version;
+    println!(
+        "Block #{} ran the upgrade's migrations:",
+        included_number + 1
+    );
+    let migrations_ok = report_events(&api, &new_meta, upgrade_block, true).await?;
+    let last_upgrade = read_value(
+        &api,
+        &new_meta,
+        "System",
+        "LastRuntimeUpgrade",
+        upgrade_block,
+    )
+    .await?
+    .unwrap_or(JsonValue::Null);
+    println!(
+        "Spec version {old_version} -> {new_version}; System.LastRuntimeUpgrade is {last_upgrade}"
+    );
+
+    if new_version <= old_version {
+        return Err(eyre!("the spec version did not move forward"));
+    }
+    if last_upgrade["spec_version"] != new_version {
+        return Err(eyre!(
+            "System.LastRuntimeUpgrade does not record spec version {new_version}"
+        ));
+    }
+    if !migrations_ok {
+        return Err(eyre!("the upgrade block has failure events"));
+    }
+
+    if let Some(script) = &args.then {
+        println!("Running {}", script.display());
+        let status = Command::new(script)
+            .env("FORK_RPC_URL", node.rpc_url())
+            .status()
+            .await
+            .map_err(|e| eyre!("failed to run {}: {e}", script.display()))?;
+        if !status.success() {
+            return Err(eyre!("{} exited with {status}", script.display()));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn failed_sudo_calls_and_extrinsics_are_failures() {
+        let sudid = |result: JsonValue| json!({"phase": {"ApplyExtrinsic": 1}, "event": {"Sudo": {"Sudid": {"sudo_result": result}}}, "topics": []});
+        let (name, _, failed) = describe_event(&sudid(json!("Ok"))).unwrap();
+        assert_eq!(name, "Sudo.Sudid");
+        assert!(!failed);
+        assert!(
+            describe_event(&sudid(json!({"Err": {"BadOrigin": null}})))
+                .unwrap()
+                .2
+        );
+        let updated = json!({"phase": "Initialization", "event": {"System": "CodeUpdated"}});
+        assert_eq!(
+            describe_event(&updated),
+            Some(("System.CodeUpdated".to_owned(), JsonValue::Null, false))
+        );
+    }
+}
@@ -0,0 +1,636 @@
+use std::collections::{BTreeMap, HashMap};
+
+use codec::{Decode, Encode};
+use color_eyre::{eyre::eyre, Result};
+use console::style;
+use frame_metadata::RuntimeMetadataV14;
+use scale_info::TypeDef;
+use serde_json::{json, Value as JsonValue};
+use sp_core::crypto::{AccountId32, Ss58Codec};
+use sp_core::{ecdsa, ed25519, sr25519, Pair};
+
+use crate::cli::Cli;
+use crate::metadata::{self, put_typed, StorageItem};
+use crate::scale::{self, default_json, set_field};
+use crate::{json_hex_bytes, storage_prefix, system_account_storage_key, ToHex};
+
+/// `pallet_staking`'s balance lock id.
+const STAKING_LOCK_ID: &str = "staking ";
+
+/// Well-known key of the GRANDPA authority set in runtimes that predate the
+/// `Grandpa.Authorities` storage item.
+const GRANDPA_AUTHORITIES_KEY: &[u8] = b":grandpa_authorities";
+
+/// A validator of the fork, derived from a secret URI such as `//Alice`. Its
+/// account is both stash and controller, and its session keys are derived
+/// from the same URI, so a node started with `--alice` (or with the URI's
+/// keys inserted) authors blocks for it.
+pub struct Validator {
+    pub uri: String,
+    pub account: [u8; 32],
+}
+
+/// The validators from `--validators-file` and `--validators`, in that order.
+pub fn validators_from_cli(cli: &Cli) -> Result<Vec<Validator>> {
+    let mut uris = Vec::new();
+    if let Some(path) = &cli.validators_file {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| eyre!("failed to read {}: {e}", path.display()))?;
+        uris.extend(
+            text.lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(str::to_owned),
+        );
+    }
+    uris.extend(cli.validators.iter().flatten().cloned());
+
+    let mut validators: Vec<Validator> = Vec::with_capacity(uris.len());
+    for uri in uris {
+        let account = sr25519::Pair::from_string(&uri, None)
+            .map_err(|e| eyre!("invalid validator secret URI `{uri}`: {e:?}"))?
+            .public()
+            .0;
+        if validators.iter().any(|v| v.account == account) {
+            return Err(eyre!("validator `{uri}` is listed more than once"));
+        }
+        validators.push(Validator { uri, account });
+    }
+    Ok(validators)
+}
+
+#[derive(Clone, Copy)]
+enum KeyCrypto {
+    Sr25519,
+    Ed25519,
+    Ecdsa,
+}
+
+/// The key type id and crypto of a session key, from the type path of its
+/// field in the runtime's `SessionKeys`.
+fn session_key_kind(type_path: &str) -> Option<(&'static str, KeyCrypto)> {
+    [
+        ("babe", "babe", KeyCrypto::Sr25519),
+        ("grandpa", "gran", KeyCrypto::Ed25519),
+        ("im_online", "imon", KeyCrypto::Sr25519),
+        ("authority_discovery", "audi", KeyCrypto::Sr25519),
+        ("beefy", "beef", KeyCrypto::Ecdsa),
+    ]
+    .into_iter()
+    .find(|(needle, _, _)| type_path.contains(needle))
+    .map(|(_, id, crypto)| (id, crypto))
+}
+
+fn derive_public(uri: &str, crypto: KeyCrypto) -> Result<Vec<u8>> {
+    let err = |e| eyre!("failed to derive a session key from `{uri}`: {e:?}");
+    Ok(match crypto {
+        KeyCrypto::Sr25519 => sr25519::Pair::from_string(uri, None)
+            .map_err(err)?
+            .public()
+            .0
+            .to_vec(),
+        KeyCrypto::Ed25519 => ed25519::Pair::from_string(uri, None)
+            .map_err(err)?
+            .public()
+            .0
+            .to_vec(),
+        KeyCrypto::Ecdsa => ecdsa::Pair::from_string(uri, None)
+            .map_err(err)?
+            .public()
+            .0
+            .to_vec(),
+    })
+}
+
+/// One session key of a validator: the `SessionKeys` field, key type id and
+/// public key.
+struct SessionKey {
+    field: String,
+    key_type: &'static str,
+    public: Vec<u8>,
+}
+
+fn session_keys(meta: &RuntimeMetadataV14, uri: &str) -> Result<Vec<SessionKey>> {
+    let next_keys = metadata::storage_item(meta, "Session", "NextKeys")?;
+    let keys_ty = scale::resolve(&meta.types, next_keys.value_type())?;
+    let TypeDef::Composite(composite) = keys_ty.type_def() else {
+        return Err(eyre!(
+            "unexpected session keys type `{}`",
+            scale::type_name(keys_ty)
+        ));
+    };
+    composite
+        .fields()
+        .iter()
+        .map(|field| {
+            let name = field
+                .name()
+                .ok_or_else(|| eyre!("session keys have unnamed fields"))?;
+            let path = scale::type_name(scale::resolve(&meta.types, field.ty().id())?);
+            let (key_type, crypto) = session_key_kind(&path)
+                .ok_or_else(|| eyre!("unsupported session key `{name}` ({path})"))?;
+            Ok(SessionKey {
+                field: name.clone(),
+                key_type,
+                public: derive_public(uri, crypto)?,
+            })
+        })
+        .collect()
+}
+
+/// Writes typed values for the storage items the runtime has, skipping the
+/// ones it doesn't (item sets differ between runtime versions).
+struct Writer<'a> {
+    meta: &'a RuntimeMetadataV14,
+    overrides: &'a mut serde_json::Map<String, JsonValue>,
+}
+
+impl<'a> Writer<'a> {
+    fn item(&self, pallet: &str, item: &str) -> Option<StorageItem<'a>> {
+        metadata::find_item(self.meta, pallet, item)
+    }
+
+    /// The zero value of `pallet.item`, to fill in by field name.
+    fn template(&self, pallet: &str, item: &str) -> Result<Option<JsonValue>> {
+        self.item(pallet, item)
+            .map(|found| default_json(&self.meta.types, found.value_type()))
+            .transpose()
+    }
+
+    fn put(
+        &mut self,
+        pallet: &str,
+        item: &str,
+        args: &[JsonValue],
+        value: &JsonValue,
+    ) -> Result<bool> {
+        let Some(found) = metadata::find_item(self.meta, pallet, item) else {
+            return Ok(false);
+        };
+        put_typed(self.meta, self.overrides, &found, args, value)
+            .map_err(|e| eyre!("{pallet}.{item}: {e}"))?;
+        Ok(true)
+    }
+}
+
+/// Staking's configuration, which the injected genesis leaves as it was.
+const STAKING_SETTINGS: [&str; 11] = [
+    "MinNominatorBond",
+    "MinValidatorBond",
+    "MinimumActiveStake",
+    "MinCommission",
+    "MaxValidatorsCount",
+    "MaxNominatorsCount",
+    "ChillThreshold",
+    "ForceEra",
+    "SlashRewardFraction",
+    "CanceledSlashPayout",
+    "MaxStakedRewards",
+];
+
+/// Keys of the validator `pallets`' state that isn't their authority set or
+/// stakers: each pallet's storage version and Staking's configuration.
+pub fn setting_keys(pallets: &[&str]) -> Vec<String> {
+    pallets
+        .iter()
+        .map(|pallet| storage_prefix(pallet, ":__STORAGE_VERSION__:"))
+        .chain(
+            STAKING_SETTINGS
+                .iter()
+                .map(|item| storage_prefix("Staking", item)),
+        )
+        .collect()
+}
+
+/// Carry the validator `pallets`' settings (see [`setting_keys`]) over to the
+/// fork, from the original chain's state or else the base spec, as the
+/// injected genesis replaces the rest of their state. Without their storage
+/// versions, migrations would take every pallet for a fresh one.
+pub fn carry_settings(
+    pallets: &[&str],
+    original: &HashMap<String, String>,
+    base: &serde_json::Map<String, JsonValue>,
+    overrides: &mut serde_json::Map<String, JsonValue>,
+) {
+    for key in setting_keys(pallets) {
+        let value = original
+            .get(&key)
+            .map(|v| JsonValue::from(v.as_str()))
+            .or_else(|| base.get(&key).cloned());
+        if let Some(value) = value {
+            overrides.entry(key).or_insert(value);
+        }
+    }
+}
+
+/// What the injected validator genesis needs from the rest of the fork.
+pub struct ValidatorGenesis<'a> {
+    pub validators: &'a [Validator],
+    /// Bonded (and locked) by each validator.
+    pub stake: u128,
+    /// SCALE hex of `Babe.EpochConfig`, carried over from the original chain.
+    pub babe_epoch_config: Option<String>,
+    /// The fork's total issuance, for `VoterList` scores.
+    pub total_issuance: u128,
+}
+
+/// Write consistent Babe, Grandpa, Session, ImOnline, AuthorityDiscovery,
+/// Staking, VoterList and staking-lock genesis for `genesis.validators`,
+/// replacing the original chain's authority set. Each validator's
+/// `System.Account` must already be in `overrides` (funded).
+pub fn inject_validator_genesis(
+    meta: &RuntimeMetadataV14,
+    genesis: &ValidatorGenesis<'_>,
+    overrides: &mut serde_json::Map<String, JsonValue>,
+) -> Result<()> {
+    let validators = genesis.validators;
+    let stake = genesis.stake.to_string();
+    let accounts: Vec<JsonValue> = validators
+        .iter()
+        .map(|v| v.account.to_hex().into())
+        .collect();
+    let keys = validators
+        .iter()
+        .map(|v| session_keys(meta, &v.uri))
+        .collect::<Result<Vec<_>>>()?;
+    let public_keys = |key_type: &str| -> Vec<String> {
+        keys.iter()
+            .flat_map(|ks| ks.iter().filter(|k| k.key_type == key_type))
+            .map(|k| k.public.to_hex())
+            .collect()
+    };
+    let babe = public_keys("babe");
+    let grandpa = public_keys("gran");
+    if babe.is_empty() || grandpa.is_empty() {
+        return Err(eyre!(
+            "the runtime's session keys must include babe and grandpa keys"
+        ));
+    }
+
+    let mut w = Writer { meta, overrides };
+
+    // Session
+    let keys_json: Vec<JsonValue> = keys
+        .iter()
+        .map(|ks| {
+            JsonValue::Object(
+                ks.iter()
+                    .map(|k| (k.field.clone(), k.public.to_hex().into()))
+                    .collect(),
+            )
+        })
+        .collect();
+    w.put(
+        "Session",
+        "Validators",
+        &[],
+        &JsonValue::Array(accounts.clone()),
+    )?;
+    let queued: Vec<JsonValue> = accounts
+        .iter()
+        .zip(&keys_json)
+        .map(|(account, ks)| json!([account, ks]))
+        .collect();
+    w.put("Session", "QueuedKeys", &[], &JsonValue::Array(queued))?;
+    for ((account, ks), session_keys) in accounts.iter().zip(&keys_json).zip(&keys) {
+        w.put("Session", "NextKeys", &[account.clone()], ks)?;
+        for key in session_keys {
+            w.put(
+                "Session",
+                "KeyOwner",
+                &[json!([key.key_type, key.public.to_hex()])],
+                account,
+            )?;
+        }
+    }
+
+    // Babe and Grandpa
+    let weighted = |keys: &[String]| JsonValue::Array(keys.iter().map(|k| json!([k, 1])).collect());
+    w.put("Babe", "Authorities", &[], &weighted(&babe))?;
+    w.put("Babe", "NextAuthorities", &[], &weighted(&babe))?;
+    match &genesis.babe_epoch_config {
+        Some(config) => {
+            w.overrides
+                .insert(storage_prefix("Babe", "EpochConfig"), config.clone().into());
+        }
+        None => println!(
+            "{}",
+            style(
+                "Warning: no Babe.EpochConfig on the original chain or base spec; the fork will \
+                 not author blocks"
+            )
+            .yellow()
+        ),
+    }
+    if !w.put("Grandpa", "Authorities", &[], &weighted(&grandpa))? {
+        let list: Vec<([u8; 32], u64)> = grandpa
+            .iter()
+            .map(|k| {
+                let mut public = [0u8; 32];
+                hex::decode_to_slice(&k[2..], &mut public).expect("ed25519 public key");
+                (public, 1)
+            })
+            .collect();
+        // `VersionedAuthorityList`: version 1 followed by the list.
+        w.overrides.insert(
+            GRANDPA_AUTHORITIES_KEY.to_hex(),
+            (1u8, list).encode().to_hex().into(),
+        );
+    }
+
+    // Other session-keyed authority sets
+    let im_online = public_keys("imon");
+    if !im_online.is_empty() {
+        w.put("ImOnline", "Keys", &[], &json!(im_online))?;
+    }
+    let discovery = public_keys("audi");
+    if !discovery.is_empty() {
+        w.put("AuthorityDiscovery", "Keys", &[], &json!(discovery))?;
+        w.put("AuthorityDiscovery", "NextKeys", &[], &json!(discovery))?;
+    }
+
+    // Staking: every validator bonds `stake` from its own account and is
+    // invulnerable, with era 0 active.
+    let count = json!(validators.len());
+    w.put("Staking", "ValidatorCount", &[], &count)?;
+    w.put("Staking", "MinimumValidatorCount", &[], &json!(1))?;
+    w.put(
+        "Staking",
+        "Invulnerables",
+        &[],
+        &JsonValue::Array(accounts.clone()),
+    )?;
+    w.put("Staking", "CounterForValidators", &[], &count)?;
+    w.put("Staking", "CurrentEra", &[], &json!(0))?;
+    w.put(
+        "Staking",
+        "ActiveEra",
+        &[],
+        &json!({ "index": 0, "start": null }),
+    )?;
+    w.put("Staking", "ErasStartSessionIndex", &[json!(0)], &json!(0))?;
+    let total_stake = genesis.stake.saturating_mul(validators.len() as u128);
+    w.put(
+        "Staking",
+        "ErasTotalStake",
+        &[json!(0)],
+        &json!(total_stake.to_string()),
+    )?;
+    let prefs = w
+        .template("Staking", "Validators")?
+        .unwrap_or(JsonValue::Null);
+    for account in &accounts {
+        w.put("Staking", "Bonded", &[account.clone()], account)?;
+        w.put("Staking", "Payee", &[account.clone()], &json!("Staked"))?;
+        w.put("Staking", "Validators", &[account.clone()], &prefs)?;
+        w.put(
+            "Staking",
+            "ErasValidatorPrefs",
+            &[json!(0), account.clone()],
+            &prefs,
+        )?;
+        if let Some(mut ledger) = w.template("Staking", "Ledger")? {
+            set_field(&mut ledger, "stash", account.clone());
+            set_field(&mut ledger, "total", json!(stake));
+            set_field(&mut ledger, "active", json!(stake));
+            w.put("Staking", "Ledger", &[account.clone()], &ledger)?;
+        }
+        if let Some(mut exposure) = w.template("Staking", "ErasStakers")? {
+            set_field(&mut exposure, "total", json!(stake));
+            set_field(&mut exposure, "own", json!(stake));
+            w.put(
+                "Staking",
+                "ErasStakers",
+                &[json!(0), account.clone()],
+                &exposure,
+            )?;
+        }
+        if let Some(mut overview) = w.template("Staking", "ErasStakersOverview")? {
+            set_field(&mut overview, "total", json!(stake));
+            set_field(&mut overview, "own", json!(stake));
+            w.put(
+                "Staking",
+                "ErasStakersOverview",
+                &[json!(0), account.clone()],
+                &overview,
+            )?;
+        }
+        if let Some(locks) = w.item("Balances", "Locks") {
+            let lock_ty = scale::element_type(&meta.types, locks.value_type())?
+                .ok_or_else(|| eyre!("unexpected Balances.Locks type"))?;
+            let mut lock = default_json(&meta.types, lock_ty)?;
+            set_field(&mut lock, "id", json!(STAKING_LOCK_ID));
+            set_field(&mut lock, "amount", json!(stake));
+            set_field(&mut lock, "reasons", json!("All"));
+            w.put("Balances", "Locks", &[account.clone()], &json!([lock]))?;
+        }
+    }
+
+    // The session keys and the staking lock each hold a consumer reference,
+    // and the lock freezes the bonded balance.
+    for v in validators {
+        let key = system_account_storage_key(&v.account);
+        let mut info = w
+            .overrides
+            .get(&key)
+            .and_then(json_hex_bytes)
+            .ok_or_else(|| eyre!("validator account {key} is not funded"))?;
+        if info.len() >= 64 {
+            let consumers = u32::from_le_bytes(info[4..8].try_into().expect("4 bytes")).max(2);
+            info[4..8].copy_from_slice(&consumers.to_le_bytes());
+            let frozen =
+                u128::from_le_bytes(info[48..64].try_into().expect("16 bytes")).max(genesis.stake);
+            info[48..64].copy_from_slice(&frozen.to_le_bytes());
+        }
+        w.overrides.insert(key, info.to_hex().into());
+    }
+
+    let voters: Vec<([u8; 32], u128)> = validators
+        .iter()
+        .map(|v| (v.account, genesis.stake))
+        .collect();
+    rebuild_voter_list(meta, &voters, genesis.total_issuance, &mut *w.overrides)?;
+
+    let names: Vec<String> = validators
+        .iter()
+        .map(|v| {
+            format!(
+                "{} ({})",
+                v.uri,
+                AccountId32::from(v.account).to_ss58check()
+            )
+        })
+        .collect();
+    println!(
+        "Injected validator genesis for {} validator(s): {}",
+        validators.len(),
+        names.join(", ")
+    );
+    Ok(())
+}
+
+/// `CurrencyToVote` as `U128CurrencyToVote`: stake scaled down so the total
+/// issuance fits in a `u64`.
+fn vote_weight(stake: u128, total_issuance: u128) -> u64 {
+    let factor = (total_issuance / u128::from(u64::MAX)).max(1);
+    u64::try_from(stake / factor).unwrap_or(u64::MAX)
+}
+
+/// The upper bound of the bag holding `score`: the first threshold at or
+/// above it, or `u64::MAX`.
+fn bag_upper(thresholds: &[u64], score: u64) -> u64 {
+    thresholds
+        .iter()
+        .copied()
+        .find(|&t| t >= score)
+        .unwrap_or(u64::MAX)
+}
+
+/// Rebuild the bags-list `VoterList` in `overrides` for `voters` (account and
+/// bonded stake), replacing any list nodes and bags already there: voters are
+/// grouped into bags by score and each bag's nodes linked in order.
+pub fn rebuild_voter_list(
+    meta: &RuntimeMetadataV14,
+    voters: &[([u8; 32], u128)],
+    total_issuance: u128,
+    overrides: &mut serde_json::Map<String, JsonValue>,
+) -> Result<()> {
+    if metadata::find_item(meta, "VoterList", "ListNodes").is_none() {
+        return Ok(());
+    }
+    let nodes_prefix = storage_prefix("VoterList", "ListNodes");
+    let bags_prefix = storage_prefix("VoterList", "ListBags");
+    overrides.retain(|k, _| !k.starts_with(&nodes_prefix) && !k.starts_with(&bags_prefix));
+
+    let thresholds = metadata::constant(meta, "VoterList", "BagThresholds")
+        .map(|mut bytes| Vec::<u64>::decode(&mut bytes))
+        .transpose()?
+        .unwrap_or_default();
+    let mut bags: BTreeMap<u64, Vec<(JsonValue, u64)>> = BTreeMap::new();
+    for (account, stake) in voters {
+        let score = vote_weight(*stake, total_issuance);
+        bags.entry(bag_upper(&thresholds, score))
+            .or_default()
+            .push((account.to_hex().into(), score));
+    }
+
+    let mut w = Writer { meta, overrides };
+    for (upper, members) in &bags {
+        for (i, (account, score)) in members.iter().enumerate() {
+            let mut node = w
+                .template("VoterList", "ListNodes")?
+                .expect("ListNodes exists");
+            set_field(&mut node, "id", account.clone());
+            if i > 0 {
+                set_field(&mut node, "prev", members[i - 1].0.clone());
+            }
+            if let Some((next, _)) = members.get(i + 1) {
+                set_field(&mut node, "next", next.clone());
+            }
+            set_field(&mut node, "bag_upper", json!(upper));
+            set_field(&mut node, "score", json!(score));
+            w.put("VoterList", "ListNodes", &[account.clone()], &node)?;
+        }
+        if let Some(mut bag) = w.template("VoterList", "ListBags")? {
+            set_field(&mut bag, "head", members[0].0.clone());
+            set_field(&mut bag, "tail", members[members.len() - 1].0.clone());
+            w.put("VoterList", "ListBags", &[json!(upper)], &bag)?;
+        }
+    }
+    w.put(
+        "VoterList",
+        "CounterForListNodes",
+        &[],
+        &json!(voters.len()),
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{account_id_from_seed_hex, ALICE_SEED_HEX};
+
+    #[test]
+    fn session_key_kinds_follow_type_paths() {
+        assert!(matches!(
+            session_key_kind("sp_consensus_babe::app::Public"),
+            Some(("babe", KeyCrypto::Sr25519))
+        ));
+        assert!(matches!(
+            session_key_kind("sp_consensus_grandpa::app::Public"),
+            Some(("gran", KeyCrypto::Ed25519))
+        ));
+        assert!(matches!(
+            session_key_kind("pallet_im_online::sr25519::app_sr25519::Public"),
+            Some(("imon", KeyCrypto::Sr25519))
+        ));
+        assert!(session_key_kind("sp_core::crypto::AccountId32").is_none());
+    }
+
+    #[test]
+    fn validator_accounts_derive_from_secret_uris() {
+        let alice = sr25519::Pair::from_string("//Alice", None)
+            .unwrap()
+            .public()
+            .0;
+        assert_eq!(alice, account_id_from_seed_hex(ALICE_SEED_HEX).unwrap());
+        // The well-known //Alice GRANDPA key.
+        assert_eq!(
+            derive_public("//Alice", KeyCrypto::Ed25519)
+                .unwrap()
+                .to_hex(),
+            "0x88dc3417d5058ec4b4503e0c12ea1a0a89be200fe98922423d4334014fa6b0ee"
+        );
+    }
+
+    #[test]
+    fn storage_versions_and_staking_settings_are_carried_over() {
+        let version = |pallet| storage_prefix(pallet, ":__STORAGE_VERSION__:");
+        let original = HashMap::from([
+            (version("Staking"), "0x0e00".to_owned()),
+            (
+                storage_prefix("Staking", "MinValidatorBond"),
+                "0x01".to_owned(),
+            ),
+            (storage_prefix("Staking", "Bonded"), "0x02".to_owned()),
+        ]);
+        let base: serde_json::Map<String, JsonValue> = [
+            (version("Staking"), json!("0x0100")),
+            (version("Babe"), json!("0x0300")),
+            (
+                storage_prefix("Staking", "MaxNominatorsCount"),
+                json!("0x04"),
+            ),
+        ]
+        .into_iter()
+        .collect();
+        let mut overrides = serde_json::Map::new();
+        carry_settings(&["Babe", "Staking"], &original, &base, &mut overrides);
+
+        assert_eq!(overrides[&version("Staking")], json!("0x0e00"));
+        assert_eq!(overrides[&version("Babe")], json!("0x0300"));
+        assert_eq!(
+            overrides[&storage_prefix("Staking", "MinValidatorBond")],
+            json!("0x01")
+        );
+        assert_eq!(
+            overrides[&storage_prefix("Staking", "MaxNominatorsCount")],
+            json!("0x04")
+        );
+        assert!(!overrides.contains_key(&storage_prefix("Staking", "Bonded")));
+    }
+
+    #[test]
+    fn scores_land_in_the_first_bag_that_fits() {
+        assert_eq!(vote_weight(1000, 1_000_000), 1000);
+        assert_eq!(
+            vote_weight(u128::from(u64::MAX) * 4, u128::from(u64::MAX) * 8),
+            u64::MAX / 2
+        );
+        assert_eq!(bag_upper(&[10, 100, 1000], 100), 100);
+        assert_eq!(bag_upper(&[10, 100, 1000], 101), 1000);
+        assert_eq!(bag_upper(&[10, 100, 1000], 1001), u64::MAX);
+        assert_eq!(bag_upper(&[], 5), u64::MAX);
+    }
+}
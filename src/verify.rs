@@ -0,0 +1,350 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use codec::{Compact, Decode};
+use color_eyre::{eyre::eyre, Result};
+use console::style;
+use serde::de::{DeserializeSeed, IgnoredAny, MapAccess, Visitor};
+use serde_json::Value as JsonValue;
+use sp_core::crypto::{AccountId32, Ss58Codec};
+
+use crate::accounts::account_total;
+use crate::cli::VerifyArgs;
+use crate::runtime::{WASM_MAGIC, ZSTD_PREFIX};
+use crate::{
+    module_prefix, pool_bonded_account, pool_id_from_bonded_pools_key, staking_ledger_key,
+    storage_prefix, u128_le_from_first_16, ErrorInto, ToHex,
+};
+
+/// What a fork's chain spec is checked against.
+pub struct Expectations<'a> {
+    /// The original chain's genesis, which the fork's must differ from.
+    pub original_top: Option<&'a serde_json::Map<String, JsonValue>>,
+    /// Prefixes that must have no keys, except those in `allowed`.
+    pub excluded: Vec<String>,
+    pub allowed: Option<&'a serde_json::Map<String, JsonValue>>,
+    /// Pallets the fork's governance was handed over through: `Sudo` must
+    /// have its `Key` set, and collective or membership pallets their
+    /// `Members`.
+    pub governance: Vec<String>,
+}
+
+/// Turn `verify`'s arguments into expectations: excluded prefixes are hex or
+/// `Pallet`/`Pallet.Item` names.
+pub fn run(args: &VerifyArgs) -> Result<()> {
+    let original = args
+        .original
+        .as_deref()
+        .map(|path| -> Result<crate::ChainSpec> {
+            let file = File::open(path).map_err(|e| eyre!("{}: {e}", path.display()))?;
+            serde_json::from_reader(BufReader::new(file)).err_into()
+        })
+        .transpose()?;
+    let excluded = args
+        .excluded
+        .iter()
+        .flatten()
+        .map(|prefix| match prefix.split_once('.') {
+            _ if prefix.starts_with("0x") => prefix.to_lowercase(),
+            Some((pallet, item)) => storage_prefix(pallet, item),
+            None => module_prefix(prefix),
+        })
+        .collect();
+    verify_spec(
+        &args.spec,
+        &Expectations {
+            original_top: original.as_ref().map(|spec| &spec.genesis.raw.top),
+            excluded,
+            allowed: None,
+            governance: if args.no_sudo {
+                Vec::new()
+            } else {
+                vec!["Sudo".to_owned()]
+            },
+        },
+    )
+}
+
+/// Facts about a fork's top state, gathered in one streaming pass.
+#[derive(Default)]
+struct Findings {
+    code: Option<Vec<u8>>,
+    babe_authorities: Option<usize>,
+    grandpa_authorities: Option<usize>,
+    sudo_key: Option<Vec<u8>>,
+    /// Member counts of the governing collectives, by pallet.
+    members: HashMap<String, usize>,
+    total_issuance: Option<u128>,
+    account_balances: u128,
+    accounts: usize,
+    pool_ids: Vec<u32>,
+    ledgers: HashSet<String>,
+    excluded_keys: Vec<String>,
+    keys: usize,
+    /// Keys whose value matches the original genesis.
+    same_as_original: usize,
+}
+
+fn vec_len(value: &[u8]) -> Option<usize> {
+    Compact::<u32>::decode(&mut &value[..])
+        .ok()
+        .map(|len| len.0 as usize)
+}
+
+/// Check the fork's chain spec at `spec_path`, printing each check, and fail
+/// if any of them fails.
+pub fn verify_spec(spec_path: &Path, expect: &Expectations<'_>) -> Result<()> {
+    println!("Verifying {}", spec_path.display());
+    let code_key = b":code".to_hex();
+    let babe_key = storage_prefix("Babe", "Authorities");
+    let grandpa_key = storage_prefix("Grandpa", "Authorities");
+    let legacy_grandpa_key = b":grandpa_authorities".to_hex();
+    let sudo_key = storage_prefix("Sudo", "Key");
+    let issuance_key = storage_prefix("Balances", "TotalIssuance");
+    let account_prefix = storage_prefix("System", "Account");
+    let bonded_pools_prefix = storage_prefix("NominationPools", "BondedPools");
+    let ledger_prefix = storage_prefix("Staking", "Ledger");
+    let members_keys: Vec<(String, &str)> = expect
+        .governance
+        .iter()
+        .filter(|pallet| *pallet != "Sudo")
+        .map(|pallet| (storage_prefix(pallet, "Members"), pallet.as_str()))
+        .collect();
+
+    let mut found = Findings::default();
+    let mut visit = |key: &str, value: &str| {
+        found.keys += 1;
+        if let Some(original) = expect.original_top {
+            if original.get(key).and_then(JsonValue::as_str) == Some(value) {
+                found.same_as_original += 1;
+            }
+        }
+        if expect.excluded.iter().any(|p| key.starts_with(p.as_str()))
+            && !expect
+                .allowed
+                .is_some_and(|allowed| allowed.contains_key(key))
+        {
+            found.excluded_keys.push(key.to_owned());
+        }
+        let bytes = || hex::decode(value.trim_start_matches("0x")).unwrap_or_default();
+        if key == code_key {
+            found.code = Some(bytes());
+        } else if key == babe_key {
+            found.babe_authorities = vec_len(&bytes());
+        } else if key == grandpa_key {
+            found.grandpa_authorities = vec_len(&bytes());
+        } else if key == legacy_grandpa_key && found.grandpa_authorities.is_none() {
+            // `VersionedAuthorityList`: a version byte, then the list.
+            found.grandpa_authorities = bytes().get(1..).and_then(vec_len);
+        } else if key == sudo_key {
+            found.sudo_key = Some(bytes());
+        } else if let Some((_, pallet)) = members_keys.iter().find(|(k, _)| k == key) {
+            found
+                .members
+                .insert((*pallet).to_owned(), vec_len(&bytes()).unwrap_or(0));
+        } else if key == issuance_key {
+            found.total_issuance = u128_le_from_first_16(&bytes());
+        } else if key.starts_with(&account_prefix) {
+            found.accounts += 1;
+            found.account_balances = found
+                .account_balances
+                .saturating_add(account_total(&bytes()).unwrap_or(0));
+        } else if key.starts_with(&bonded_pools_prefix) {
+            found
+                .pool_ids
+                .extend(pool_id_from_bonded_pools_key(key, &bonded_pools_prefix));
+        } else if key.starts_with(&ledger_prefix) {
+            found.ledgers.insert(key.to_owned());
+        }
+    };
+    let file = File::open(spec_path).map_err(|e| eyre!("{}: {e}", spec_path.display()))?;
+    let mut de = serde_json::Deserializer::from_reader(BufReader::new(file));
+    TopEntries {
+        path: &["genesis", "raw", "top"],
+        visit: &mut visit,
+    }
+    .deserialize(&mut de)?;
+
+    let mut failures = 0usize;
+    let mut check = |ok: bool, what: String| {
+        if ok {
+            println!("{} {what}", style("ok").green());
+        } else {
+            failures += 1;
+            println!("{} {what}", style("FAILED").red());
+        }
+    };
+
+    match &found.code {
+        Some(code) if code.starts_with(WASM_MAGIC) => check(
+            true,
+            format!(":code is a wasm module ({} bytes)", code.len()),
+        ),
+        Some(code) if code.starts_with(ZSTD_PREFIX) => check(
+            true,
+            format!(":code is a compressed wasm blob ({} bytes)", code.len()),
+        ),
+        Some(_) => check(false, ":code is not a wasm module".to_owned()),
+        None => check(false, ":code is missing".to_owned()),
+    }
+    check(
+        found.babe_authorities.unwrap_or(0) > 0,
+        format!(
+            "Babe has {} authorities",
+            found.babe_authorities.unwrap_or(0)
+        ),
+    );
+    check(
+        found.grandpa_authorities.unwrap_or(0) > 0,
+        format!(
+            "Grandpa has {} authorities",
+            found.grandpa_authorities.unwrap_or(0)
+        ),
+    );
+    for pallet in &expect.governance {
+        if pallet == "Sudo" {
+            match found.sudo_key.as_deref().map(<[u8; 32]>::try_from) {
+                Some(Ok(account)) => check(
+                    true,
+                    format!("Sudo.Key is {}", AccountId32::from(account).to_ss58check()),
+                ),
+                Some(Err(_)) => check(false, "Sudo.Key is not an account id".to_owned()),
+                None => check(false, "Sudo.Key is not set".to_owned()),
+            }
+        } else {
+            let members = found.members.get(pallet).copied().unwrap_or(0);
+            check(members > 0, format!("{pallet} has {members} member(s)"));
+        }
+    }
+    let issuance = found.total_issuance.unwrap_or(0);
+    check(
+        issuance == found.account_balances,
+        format!(
+            "TotalIssuance {issuance} vs {} in {} account(s)",
+            found.account_balances, found.accounts
+        ),
+    );
+    let pools_without_ledger: Vec<u32> = found
+        .pool_ids
+        .iter()
+        .copied()
+        .filter(|&id| {
+            !found
+                .ledgers
+                .contains(&staking_ledger_key(&pool_bonded_account(id)))
+        })
+        .collect();
+    check(
+        pools_without_ledger.is_empty(),
+        format!(
+            "{} nomination pool(s), without a staking ledger: {pools_without_ledger:?}",
+            found.pool_ids.len()
+        ),
+    );
+    if !expect.excluded.is_empty() {
+        check(
+            found.excluded_keys.is_empty(),
+            format!(
+                "{} key(s) under excluded prefixes{}",
+                found.excluded_keys.len(),
+                found
+                    .excluded_keys
+                    .first()
+                    .map(|k| format!(", e.g. {k}"))
+                    .unwrap_or_default()
+            ),
+        );
+    }
+    if let Some(original) = expect.original_top {
+        check(
+            found.same_as_original != found.keys || found.keys != original.len(),
+            "genesis differs from the original chain's".to_owned(),
+        );
+    }
+
+    if failures > 0 {
+        return Err(eyre!("{failures} check(s) failed"));
+    }
+    println!("{}", style("Fork verified").green());
+    Ok(())
+}
+
+/// Stream-deserialize a chain spec, descending through `path` and passing
+/// each entry of the map there to `visit`.
+struct TopEntries<'a, F> {
+    path: &'a [&'a str],
+    visit: &'a mut F,
+}
+
+impl<'de, F: FnMut(&str, &str)> DeserializeSeed<'de> for TopEntries<'_, F> {
+    type Value = ();
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de, F: FnMut(&str, &str)> Visitor<'de> for TopEntries<'_, F> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "a chain spec")
+    }
+
+    fn visit_map<A>(self, mut access: A) -> Result<Self::Value, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        while let Some(key) = access.next_key::<String>()? {
+            match self.path.split_first() {
+                None => (self.visit)(&key, &access.next_value::<String>()?),
+                Some((next, rest)) if key == *next => access.next_value_seed(TopEntries {
+                    path: rest,
+                    visit: &mut *self.visit,
+                })?,
+                Some(_) => {
+                    access.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn streams_the_top_map_of_a_chain_spec() {
+        let spec = r#"{
+            "name": "Fork",
+            "genesis": {"raw": {"childrenDefault": {}, "top": {"0x01": "0x02", "0x03": "0x04"}}},
+            "top": {"0xff": "0xff"}
+        }"#;
+        let mut seen = Vec::new();
+        let mut de = serde_json::Deserializer::from_str(spec);
+        TopEntries {
+            path: &["genesis", "raw", "top"],
+            visit: &mut |k: &str, v: &str| seen.push((k.to_owned(), v.to_owned())),
+        }
+        .deserialize(&mut de)
+        .unwrap();
+        assert_eq!(
+            seen,
+            [("0x01", "0x02"), ("0x03", "0x04")].map(|(k, v)| (k.to_owned(), v.to_owned()))
+        );
+    }
+
+    #[test]
+    fn authority_lists_are_counted_from_their_length_prefix() {
+        assert_eq!(vec_len(&[8, 0xaa]), Some(2));
+        assert_eq!(vec_len(&[]), None);
+    }
+}
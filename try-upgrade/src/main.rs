@@ -0,0 +1,306 @@
+//! Run a runtime's migrations (`TryRuntime_on_runtime_upgrade`) against a
+//! fork's cached state in an in-memory backend, without a node. Run through
+//! `creditcoin-fork try-upgrade`.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use clap::Parser;
+use codec::{Decode, Encode};
+use color_eyre::{eyre::eyre, Result};
+use console::style;
+use frame_metadata::{RuntimeMetadata, RuntimeMetadataPrefixed};
+use sc_executor::{HeapAllocStrategy, WasmExecutor, DEFAULT_HEAP_ALLOC_STRATEGY};
+use serde::de::{Error as _, MapAccess, Visitor};
+use serde::Deserializer as _;
+use sp_core::hashing::twox_128;
+use sp_core::traits::{CallContext, ReadRuntimeVersionExt};
+use sp_externalities::Extensions;
+use sp_runtime::traits::BlakeTwo256;
+use sp_state_machine::backend::BackendRuntimeCode;
+use sp_state_machine::{Backend as _, InMemoryBackend, OverlayedChanges, StateMachine};
+use sp_storage::{well_known_keys, StateVersion, Storage};
+use sp_weights::Weight;
+
+type Executor = WasmExecutor<sp_io::SubstrateHostFunctions>;
+
+#[derive(Parser)]
+#[clap(author, version, about)]
+struct Args {
+    /// The fork's cached state (`<out>.storage.json`)
+    #[clap(default_value = "fork.json.storage.json")]
+    storage: PathBuf,
+    /// Runtime WASM blob to upgrade to, built with the `try-runtime` feature
+    #[clap(long)]
+    runtime: PathBuf,
+    /// Run the pallets' `try_state` checks after the migrations, besides
+    /// their pre- and post-upgrade checks
+    #[clap(long)]
+    try_state: bool,
+    /// Extra heap pages for the runtime (default: the executor's)
+    #[clap(long)]
+    heap_pages: Option<u32>,
+}
+
+/// `frame_try_runtime::UpgradeCheckSelect`, as the runtime decodes it.
+#[derive(Encode)]
+enum UpgradeCheckSelect {
+    #[codec(index = 1)]
+    All,
+    #[codec(index = 2)]
+    PreAndPost,
+}
+
+/// How a storage item's keys moved during the upgrade.
+#[derive(Default)]
+struct Changes {
+    added: usize,
+    changed: usize,
+    removed: usize,
+}
+
+fn main() -> Result<()> {
+    color_eyre::install()?;
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let args = Args::parse();
+
+    println!("Loading {}", args.storage.display());
+    let mut top = read_storage(&args.storage)?;
+    let wasm =
+        std::fs::read(&args.runtime).map_err(|e| eyre!("{}: {e}", args.runtime.display()))?;
+    top.insert(well_known_keys::CODE.to_vec(), wasm);
+    println!("Loaded {} keys", top.len());
+    let storage = Storage {
+        top,
+        children_default: HashMap::new(),
+    };
+    let backend: InMemoryBackend<BlakeTwo256> = (storage, StateVersion::V1).into();
+
+    let heap = args
+        .heap_pages
+        .map_or(DEFAULT_HEAP_ALLOC_STRATEGY, |extra_pages| {
+            HeapAllocStrategy::Static { extra_pages }
+        });
+    let executor = Executor::builder()
+        .with_onchain_heap_alloc_strategy(heap)
+        .with_offchain_heap_alloc_strategy(heap)
+        .with_allow_missing_host_functions(true)
+        .build();
+
+    let names = call(&backend, &executor, "Metadata_metadata", &[])
+        .and_then(|(_, out)| Ok(Vec::<u8>::decode(&mut &out[..])?))
+        .and_then(|metadata| item_names(&metadata))
+        .unwrap_or_else(|e| {
+            println!(
+                "{}",
+                style(format!(
+                    "Warning: storage items are shown by key prefix: {e}"
+                ))
+                .yellow()
+            );
+            HashMap::new()
+        });
+
+    let checks = if args.try_state {
+        UpgradeCheckSelect::All
+    } else {
+        UpgradeCheckSelect::PreAndPost
+    };
+    println!("Running TryRuntime_on_runtime_upgrade");
+    let (mut overlay, out) = call(
+        &backend,
+        &executor,
+        "TryRuntime_on_runtime_upgrade",
+        &checks.encode(),
+    )
+    .map_err(|e| {
+        if e.to_string().contains("is not found") {
+            eyre!("{e} (is the runtime built with the `try-runtime` feature?)")
+        } else {
+            eyre!("the upgrade's migrations or checks failed: {e}")
+        }
+    })?;
+    let (weight, max_weight) = <(Weight, Weight)>::decode(&mut &out[..])?;
+
+    let storage_changes = overlay
+        .drain_storage_changes(&backend, StateVersion::V1)
+        .map_err(|e| eyre!("{e}"))?;
+    let mut by_item = BTreeMap::<String, Changes>::new();
+    for (key, value) in storage_changes.main_storage_changes {
+        let before = backend.storage(&key).map_err(|e| eyre!("{e}"))?;
+        let item = by_item.entry(describe(&key, &names)).or_default();
+        match (before, value) {
+            (None, Some(_)) => item.added += 1,
+            (Some(before), Some(after)) if before != after => item.changed += 1,
+            (Some(_), None) => item.removed += 1,
+            _ => {}
+        }
+    }
+    by_item.retain(|_, c| c.added + c.changed + c.removed > 0);
+    println!("Storage changes ({} item(s)):", by_item.len());
+    for (item, c) in &by_item {
+        println!(
+            "  {item}: {} added, {} changed, {} removed",
+            c.added, c.changed, c.removed
+        );
+    }
+
+    let share = |used: u64, max: u64| used as f64 * 100.0 / max.max(1) as f64;
+    println!(
+        "Consumed {} ref time ({:.2}% of a block) and {} proof size ({:.2}%)",
+        weight.ref_time(),
+        share(weight.ref_time(), max_weight.ref_time()),
+        weight.proof_size(),
+        share(weight.proof_size(), max_weight.proof_size()),
+    );
+    if weight.any_gt(max_weight) {
+        return Err(eyre!(
+            "the upgrade's weight {weight:?} exceeds the max block weight {max_weight:?}"
+        ));
+    }
+    println!("{}", style("Upgrade ran cleanly").green());
+    Ok(())
+}
+
+/// Call `method` on the backend's `:code`, returning its storage changes and
+/// encoded output.
+fn call(
+    backend: &InMemoryBackend<BlakeTwo256>,
+    executor: &Executor,
+    method: &str,
+    data: &[u8],
+) -> Result<(OverlayedChanges<BlakeTwo256>, Vec<u8>)> {
+    let mut overlay = OverlayedChanges::default();
+    let mut extensions = Extensions::default();
+    extensions.register(ReadRuntimeVersionExt::new(executor.clone()));
+    let runtime_code = BackendRuntimeCode::new(backend)
+        .runtime_code()
+        .map_err(|e| eyre!("{e}"))?;
+    let out = StateMachine::new(
+        backend,
+        &mut overlay,
+        executor,
+        method,
+        data,
+        &mut extensions,
+        &runtime_code,
+        CallContext::Offchain,
+    )
+    .execute()
+    .map_err(|e| eyre!("{method}: {e}"))?;
+    Ok((overlay, out))
+}
+
+/// `Pallet.Item` names of the runtime's storage items, by key prefix.
+fn item_names(metadata: &[u8]) -> Result<HashMap<Vec<u8>, String>> {
+    let mut names = HashMap::new();
+    let mut add = |pallet: &str, item: &str| {
+        let prefix = [twox_128(pallet.as_bytes()), twox_128(item.as_bytes())].concat();
+        names.insert(prefix, format!("{pallet}.{item}"));
+    };
+    match RuntimeMetadataPrefixed::decode(&mut &metadata[..])?.1 {
+        RuntimeMetadata::V14(meta) => {
+            for storage in meta.pallets.iter().filter_map(|p| p.storage.as_ref()) {
+                for entry in &storage.entries {
+                    add(&storage.prefix, &entry.name);
+                }
+            }
+        }
+        RuntimeMetadata::V15(meta) => {
+            for storage in meta.pallets.iter().filter_map(|p| p.storage.as_ref()) {
+                for entry in &storage.entries {
+                    add(&storage.prefix, &entry.name);
+                }
+            }
+        }
+        other => return Err(eyre!("unsupported metadata version {}", other.version())),
+    }
+    Ok(names)
+}
+
+/// A key's storage item: its `Pallet.Item` name, the key itself for
+/// well-known `:` keys, or else its first 32 bytes in hex.
+fn describe(key: &[u8], names: &HashMap<Vec<u8>, String>) -> String {
+    if key.starts_with(b":") {
+        return String::from_utf8_lossy(key).into_owned();
+    }
+    let prefix = &key[..key.len().min(32)];
+    names
+        .get(prefix)
+        .cloned()
+        .unwrap_or_else(|| format!("0x{}", hex::encode(prefix)))
+}
+
+fn from_hex(s: &str) -> Result<Vec<u8>, hex::FromHexError> {
+    hex::decode(s.trim_start_matches("0x"))
+}
+
+fn read_storage(path: &Path) -> Result<BTreeMap<Vec<u8>, Vec<u8>>> {
+    let file = File::open(path).map_err(|e| eyre!("{}: {e}", path.display()))?;
+    let mut de = serde_json::Deserializer::from_reader(BufReader::new(file));
+    Ok(de.deserialize_map(StorageMap)?)
+}
+
+/// Stream-deserialize the storage file straight into decoded key-value pairs.
+struct StorageMap;
+
+impl<'de> Visitor<'de> for StorageMap {
+    type Value = BTreeMap<Vec<u8>, Vec<u8>>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "a map of hex storage key-value pairs")
+    }
+
+    fn visit_map<A>(self, mut access: A) -> Result<Self::Value, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        let mut top = BTreeMap::new();
+        while let Some(key) = access.next_key::<String>()? {
+            let value = access.next_value::<String>()?;
+            top.insert(
+                from_hex(&key).map_err(A::Error::custom)?,
+                from_hex(&value).map_err(A::Error::custom)?,
+            );
+        }
+        Ok(top)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_selections_encode_like_frame_try_runtime() {
+        assert_eq!(UpgradeCheckSelect::All.encode(), [1]);
+        assert_eq!(UpgradeCheckSelect::PreAndPost.encode(), [2]);
+    }
+
+    #[test]
+    fn keys_are_described_by_their_storage_item() {
+        let prefix = [twox_128(b"System"), twox_128(b"Account")].concat();
+        let names = HashMap::from([(prefix.clone(), "System.Account".to_owned())]);
+        assert_eq!(
+            describe(&[&prefix[..], &[7; 48]].concat(), &names),
+            "System.Account"
+        );
+        assert_eq!(describe(b":code", &names), ":code");
+        assert_eq!(describe(&[0xab; 4], &names), "0xabababab");
+    }
+
+    #[test]
+    fn the_storage_file_is_decoded_from_hex() {
+        let mut de = serde_json::Deserializer::from_str(r#"{"0x0102": "0x03", "0x04": "0x"}"#);
+        let top = de.deserialize_map(StorageMap).unwrap();
+        assert_eq!(
+            top,
+            BTreeMap::from([(vec![1, 2], vec![3]), (vec![4], vec![])])
+        );
+        let mut de = serde_json::Deserializer::from_str(r#"{"0xzz": "0x"}"#);
+        assert!(de.deserialize_map(StorageMap).is_err());
+    }
+}